@@ -0,0 +1,149 @@
+//! Transmit airtime kept between runs, to stay within the duty cycle of the 433 MHz band.
+
+use crate::fs_util::write_atomic;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transmission {
+    pub timestamp: DateTime<Utc>,
+    pub airtime_ms: u32,
+}
+
+/// Transmissions of the last hour, oldest first.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AirtimeLedger {
+    transmissions: Vec<Transmission>,
+}
+
+#[derive(Debug)]
+pub enum AirtimeError {
+    IoError(std::io::Error),
+    ParseError(serde_json::Error),
+}
+
+impl std::fmt::Display for AirtimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AirtimeError::IoError(e) => write!(f, "Failed to access airtime file: {}", e),
+            AirtimeError::ParseError(e) => write!(f, "Failed to parse airtime file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AirtimeError {}
+
+impl From<std::io::Error> for AirtimeError {
+    fn from(err: std::io::Error) -> Self {
+        AirtimeError::IoError(err)
+    }
+}
+
+impl From<serde_json::Error> for AirtimeError {
+    fn from(err: serde_json::Error) -> Self {
+        AirtimeError::ParseError(err)
+    }
+}
+
+/// Airtime allowed per hour at `duty_cycle_percent`.
+pub fn budget_ms(duty_cycle_percent: f64) -> u32 {
+    (3_600_000.0 * duty_cycle_percent / 100.0) as u32
+}
+
+/// Airtime of a wake-up followed by an encoded request of `request_len` bytes, at 2.4 kbps.
+pub fn transmit_airtime_ms(wakeup_ms: u32, request_len: usize) -> u32 {
+    wakeup_ms + (request_len * 8 * 1000 / 2400) as u32
+}
+
+impl AirtimeLedger {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, AirtimeError> {
+        // only a missing file is an empty ledger, an unreadable one would hide past airtime
+        let content = match fs::read_to_string(path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            content => content?,
+        };
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), AirtimeError> {
+        write_atomic(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Airtime used over the hour before `now`.
+    pub fn used_ms(&self, now: DateTime<Utc>) -> u32 {
+        let window_start = now - TimeDelta::hours(1);
+        self.transmissions
+            .iter()
+            .filter(|t| t.timestamp > window_start)
+            .map(|t| t.airtime_ms)
+            .sum()
+    }
+
+    /// Whether `airtime_ms` more at `now` stays within `budget_ms` per hour.
+    pub fn allows(&self, airtime_ms: u32, budget_ms: u32, now: DateTime<Utc>) -> bool {
+        self.used_ms(now) + airtime_ms <= budget_ms
+    }
+
+    pub fn record(&mut self, airtime_ms: u32, now: DateTime<Utc>) {
+        let window_start = now - TimeDelta::hours(1);
+        self.transmissions.retain(|t| t.timestamp > window_start);
+        self.transmissions.push(Transmission {
+            timestamp: now,
+            airtime_ms,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+    }
+
+    #[test]
+    fn used_over_the_last_hour() {
+        let mut ledger = AirtimeLedger::default();
+        ledger.record(2130, at("2026-03-10T07:00:00Z"));
+        ledger.record(2130, at("2026-03-10T07:30:00Z"));
+
+        assert_eq!(ledger.used_ms(at("2026-03-10T07:45:00Z")), 4260);
+        assert_eq!(ledger.used_ms(at("2026-03-10T08:15:00Z")), 2130);
+
+        ledger.record(2130, at("2026-03-10T08:20:00Z"));
+        assert_eq!(ledger.transmissions.len(), 2);
+    }
+
+    #[test]
+    fn allows_within_budget() {
+        let mut ledger = AirtimeLedger::default();
+        let budget = budget_ms(0.1);
+        assert_eq!(budget, 3600);
+
+        assert!(ledger.allows(2130, budget, at("2026-03-10T07:00:00Z")));
+        ledger.record(2130, at("2026-03-10T07:00:00Z"));
+        assert!(!ledger.allows(2130, budget, at("2026-03-10T07:10:00Z")));
+        assert!(ledger.allows(2130, budget, at("2026-03-10T08:00:01Z")));
+    }
+
+    #[test]
+    fn transmit_airtime() {
+        assert_eq!(transmit_airtime_ms(2000, 39), 2130);
+    }
+
+    #[test]
+    fn load_only_defaults_a_missing_file() {
+        let path = std::env::temp_dir().join(format!("airtime_{}.json", std::process::id()));
+        assert_eq!(AirtimeLedger::load(&path).unwrap().transmissions.len(), 0);
+
+        fs::write(&path, "{\"transmissions\": [").unwrap();
+        let result = AirtimeLedger::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(AirtimeError::ParseError(_))));
+    }
+}
@@ -0,0 +1,62 @@
+//! Battery low warning and end-of-life date from the months left reported by the meter.
+
+use crate::radian::MeterData;
+use chrono::{DateTime, Months, Utc};
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct BatteryStatus {
+    pub battery_low: bool,
+    pub battery_end_of_life: Option<DateTime<Utc>>,
+}
+
+/// Estimate the battery end of life from the months remaining at read time.
+pub fn battery_status(
+    meter_data: &MeterData,
+    warning_months: u32,
+    read_at: DateTime<Utc>,
+) -> BatteryStatus {
+    let months_left = meter_data.battery_left.max(0) as u32;
+
+    BatteryStatus {
+        battery_low: months_left < warning_months,
+        battery_end_of_life: read_at.checked_add_months(Months::new(months_left)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meter_data(battery_left: i32) -> MeterData {
+        MeterData {
+            liters: 0,
+            raw_liters: 0,
+            reads_counter: 0,
+            battery_left,
+            time_start: 6,
+            time_end: 18,
+        }
+    }
+
+    #[test]
+    fn battery_status_works() {
+        let read_at = DateTime::parse_from_rfc3339("2026-01-31T07:00:00Z")
+            .unwrap()
+            .to_utc();
+
+        let status = battery_status(&meter_data(97), 12, read_at);
+        assert!(!status.battery_low);
+        assert_eq!(
+            status.battery_end_of_life.unwrap().to_rfc3339(),
+            "2034-02-28T07:00:00+00:00"
+        );
+
+        let status = battery_status(&meter_data(11), 12, read_at);
+        assert!(status.battery_low);
+
+        let status = battery_status(&meter_data(-1), 12, read_at);
+        assert!(status.battery_low);
+        assert_eq!(status.battery_end_of_life, Some(read_at));
+    }
+}
@@ -0,0 +1,303 @@
+//! SPI and GPIO access to the CC1101, through rppal on a Raspberry Pi or Linux character devices.
+
+use crate::config::RadioBackend;
+use gpio_cdev::{Chip, EventRequestFlags, LineEventHandle, LineRequestFlags};
+use log::debug;
+use nix::poll::{poll, PollFd, PollFlags};
+use rppal::{gpio, spi};
+use spidev::{SpiModeFlags, Spidev, SpidevOptions, SpidevTransfer};
+use std::cell::RefCell;
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+/// CC1101 outputs wired to the host.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Gdo {
+    Gdo0,
+    Gdo2,
+}
+
+/// Wires between the host and the CC1101: the SPI bus and the GDO0 and GDO2 outputs.
+pub trait RadioBus {
+    /// Full duplex transfer, `data` is replaced by the bytes read.
+    fn transfer(&self, data: &mut [u8]) -> Result<(), BusError>;
+
+    fn gdo_is_high(&self, gdo: Gdo) -> bool;
+
+    /// Sleep until `gdo` reads `high`, woken by its edge interrupts.
+    /// Returns false if it did not get there within `timeout`.
+    fn wait_gdo(&self, gdo: Gdo, high: bool, timeout: Duration) -> bool;
+}
+
+#[derive(Debug)]
+pub enum BusError {
+    IoError(std::io::Error),
+    GpioError(String),
+    SpiError(String),
+}
+
+impl std::fmt::Display for BusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BusError::IoError(e) => write!(f, "Failed to access radio device: {}", e),
+            BusError::GpioError(e) => write!(f, "Failed to access GPIO: {}", e),
+            BusError::SpiError(e) => write!(f, "Failed to access SPI: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BusError {}
+
+impl From<std::io::Error> for BusError {
+    fn from(err: std::io::Error) -> Self {
+        BusError::IoError(err)
+    }
+}
+
+impl From<gpio::Error> for BusError {
+    fn from(err: gpio::Error) -> Self {
+        BusError::GpioError(err.to_string())
+    }
+}
+
+impl From<gpio_cdev::Error> for BusError {
+    fn from(err: gpio_cdev::Error) -> Self {
+        BusError::GpioError(err.to_string())
+    }
+}
+
+impl From<spi::Error> for BusError {
+    fn from(err: spi::Error) -> Self {
+        BusError::SpiError(err.to_string())
+    }
+}
+
+// Edges only wake the wait up, the level is what counts: an edge queued before the wait started
+// costs one more read instead of being taken for the one awaited.
+fn wait_level(
+    high: bool,
+    timeout: Duration,
+    is_high: impl Fn() -> bool,
+    mut wait_edge: impl FnMut(Duration) -> bool,
+) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if is_high() == high {
+            return true;
+        }
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() || !wait_edge(left) {
+            return is_high() == high;
+        }
+    }
+}
+
+pub fn open(config: &RadioBackend) -> Result<Box<dyn RadioBus>, BusError> {
+    match config {
+        RadioBackend::Rppal => Ok(Box::new(RppalBus::new()?)),
+        RadioBackend::Linux {
+            spi_device,
+            gpio_chip,
+            gdo0_line,
+            gdo2_line,
+            spi_speed_hz,
+        } => Ok(Box::new(LinuxBus::new(
+            spi_device,
+            gpio_chip,
+            *gdo0_line,
+            *gdo2_line,
+            *spi_speed_hz,
+        )?)),
+    }
+}
+
+pub struct RppalBus {
+    // poll_interrupt needs the pins mutably
+    gdo0: RefCell<gpio::InputPin>,
+    gdo2: RefCell<gpio::InputPin>,
+    spi: spi::Spi,
+}
+
+impl RppalBus {
+    pub fn new() -> Result<Self, BusError> {
+        // Initialize GPIO
+        debug!("Initializing GPIO");
+        let gpio = gpio::Gpio::new()?;
+        let mut gdo0 = gpio.get(17)?.into_input(); //pin 0 (GDO0) = BCM GPIO 17 (physical pin 11)
+        let mut gdo2 = gpio.get(27)?.into_input(); //pin 2 (GDO2) = BCM GPIO 27 (physical pin 13)
+        gdo0.set_interrupt(gpio::Trigger::Both, None)?;
+        gdo2.set_interrupt(gpio::Trigger::Both, None)?;
+
+        // to use SPI pi@MinePi ~ $ gpio unload spi  then gpio load spi
+        // sinon pas de MOSI ni pas de CSn , buffer de 4kB
+        let speed = 100000u32;
+        let spi = spi::Spi::new(
+            spi::Bus::Spi0,
+            spi::SlaveSelect::Ss0,
+            speed,
+            spi::Mode::Mode0,
+        )?;
+
+        Ok(Self {
+            gdo0: RefCell::new(gdo0),
+            gdo2: RefCell::new(gdo2),
+            spi,
+        })
+    }
+
+    fn pin(&self, gdo: Gdo) -> &RefCell<gpio::InputPin> {
+        match gdo {
+            Gdo::Gdo0 => &self.gdo0,
+            Gdo::Gdo2 => &self.gdo2,
+        }
+    }
+}
+
+impl RadioBus for RppalBus {
+    fn transfer(&self, data: &mut [u8]) -> Result<(), BusError> {
+        // copy data to be written
+        let write = data.to_vec();
+        self.spi.transfer(data, &write)?;
+        Ok(())
+    }
+
+    fn gdo_is_high(&self, gdo: Gdo) -> bool {
+        self.pin(gdo).borrow().read() == gpio::Level::High
+    }
+
+    fn wait_gdo(&self, gdo: Gdo, high: bool, timeout: Duration) -> bool {
+        let pin = self.pin(gdo);
+        wait_level(
+            high,
+            timeout,
+            || self.gdo_is_high(gdo),
+            |left| {
+                matches!(
+                    pin.borrow_mut().poll_interrupt(false, Some(left)),
+                    Ok(Some(_))
+                )
+            },
+        )
+    }
+}
+
+/// Generic Linux boards: `/dev/spidevX.Y` and `/dev/gpiochipN` lines for GDO0 and GDO2.
+pub struct LinuxBus {
+    // reading an event needs the handle mutably
+    gdo0: RefCell<LineEventHandle>,
+    gdo2: RefCell<LineEventHandle>,
+    spi: Spidev,
+}
+
+impl LinuxBus {
+    pub fn new(
+        spi_device: &std::path::Path,
+        gpio_chip: &std::path::Path,
+        gdo0_line: u32,
+        gdo2_line: u32,
+        spi_speed_hz: u32,
+    ) -> Result<Self, BusError> {
+        debug!(
+            "Opening {} and lines {} and {} of {}",
+            spi_device.display(),
+            gdo0_line,
+            gdo2_line,
+            gpio_chip.display()
+        );
+        let mut chip = Chip::new(gpio_chip)?;
+        let mut edge_events = |line| {
+            chip.get_line(line)?.events(
+                LineRequestFlags::INPUT,
+                EventRequestFlags::BOTH_EDGES,
+                "hass-everblu-meter",
+            )
+        };
+        let gdo0 = edge_events(gdo0_line)?;
+        let gdo2 = edge_events(gdo2_line)?;
+
+        let mut spi = Spidev::open(spi_device)?;
+        spi.configure(
+            &SpidevOptions::new()
+                .bits_per_word(8)
+                .max_speed_hz(spi_speed_hz)
+                .mode(SpiModeFlags::SPI_MODE_0)
+                .build(),
+        )?;
+
+        Ok(Self {
+            gdo0: RefCell::new(gdo0),
+            gdo2: RefCell::new(gdo2),
+            spi,
+        })
+    }
+
+    fn line(&self, gdo: Gdo) -> &RefCell<LineEventHandle> {
+        match gdo {
+            Gdo::Gdo0 => &self.gdo0,
+            Gdo::Gdo2 => &self.gdo2,
+        }
+    }
+}
+
+impl RadioBus for LinuxBus {
+    fn transfer(&self, data: &mut [u8]) -> Result<(), BusError> {
+        let write = data.to_vec();
+        self.spi
+            .transfer(&mut SpidevTransfer::read_write(&write, data))?;
+        Ok(())
+    }
+
+    fn gdo_is_high(&self, gdo: Gdo) -> bool {
+        // a line that can no longer be read is taken as idle, the receive then times out
+        self.line(gdo).borrow().get_value().unwrap_or(0) == 1
+    }
+
+    fn wait_gdo(&self, gdo: Gdo, high: bool, timeout: Duration) -> bool {
+        let line = self.line(gdo);
+        wait_level(
+            high,
+            timeout,
+            || self.gdo_is_high(gdo),
+            |left| {
+                let mut line = line.borrow_mut();
+                let mut fds = [PollFd::new(line.as_raw_fd(), PollFlags::POLLIN)];
+                let timeout_ms = left.as_millis().clamp(1, i32::MAX as u128) as i32;
+                // consume the event so the next poll waits for a new edge
+                matches!(poll(&mut fds, timeout_ms), Ok(n) if n > 0) && line.get_event().is_ok()
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn wait_level_works() {
+        // already at the level: no edge awaited
+        assert!(wait_level(true, Duration::ZERO, || true, |_| panic!()));
+
+        // a stale edge is followed by the one that brings the level
+        let edges = Cell::new(0);
+        assert!(wait_level(
+            true,
+            Duration::from_secs(1),
+            || edges.get() >= 2,
+            |_| {
+                edges.set(edges.get() + 1);
+                true
+            }
+        ));
+        assert_eq!(edges.get(), 2);
+
+        // no edge before the timeout
+        assert!(!wait_level(
+            false,
+            Duration::from_millis(10),
+            || true,
+            |_| false
+        ));
+    }
+}
@@ -0,0 +1,158 @@
+//! Raw receive buffers saved from a read, to replay them through the decoder without hardware.
+
+use crate::exchange::LinkQuality;
+use crate::radian::{decode_4bitpbit_serial, parse_meter_report, MeterData};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameKind {
+    Ack,
+    Data,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CapturedFrame {
+    pub kind: FrameKind,
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub link_quality: LinkQuality,
+    pub byte_count: u16,
+    #[serde(with = "hex")]
+    pub rx_buffer: Vec<u8>,
+}
+
+/// Raw receive buffers of one exchange with a meter, replayable without hardware.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Capture {
+    pub timestamp: DateTime<Utc>,
+    pub year: u8,
+    pub serial: u32,
+    #[serde(with = "hex")]
+    pub registers: Vec<u8>,
+    pub frames: Vec<CapturedFrame>,
+}
+
+#[derive(Debug)]
+pub enum CaptureError {
+    IoError(std::io::Error),
+    ParseError(serde_json::Error),
+}
+
+impl std::fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CaptureError::IoError(e) => write!(f, "Failed to access capture file: {}", e),
+            CaptureError::ParseError(e) => write!(f, "Failed to parse capture file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {}
+
+impl From<std::io::Error> for CaptureError {
+    fn from(err: std::io::Error) -> Self {
+        CaptureError::IoError(err)
+    }
+}
+
+impl From<serde_json::Error> for CaptureError {
+    fn from(err: serde_json::Error) -> Self {
+        CaptureError::ParseError(err)
+    }
+}
+
+impl Capture {
+    pub fn new(year: u8, serial: u32) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            year,
+            serial,
+            registers: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn push_frame(
+        &mut self,
+        kind: FrameKind,
+        link_quality: LinkQuality,
+        rx_buffer: &[u8],
+        byte_count: u16,
+    ) {
+        self.frames.push(CapturedFrame {
+            kind,
+            timestamp: Utc::now(),
+            link_quality,
+            byte_count,
+            rx_buffer: rx_buffer[..byte_count as usize].to_vec(),
+        });
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, CaptureError> {
+        let content = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    /// Write the capture in `dir`, named after its timestamp and meter serial.
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> Result<PathBuf, CaptureError> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+
+        let path = dir.join(format!(
+            "{}-{}.json",
+            self.timestamp.format("%Y%m%dT%H%M%SZ"),
+            self.serial
+        ));
+        fs::write(&path, serde_json::to_string_pretty(self)?)?;
+
+        Ok(path)
+    }
+}
+
+impl CapturedFrame {
+    pub fn decode(&self) -> Vec<u8> {
+        decode_4bitpbit_serial(&self.rx_buffer, self.byte_count)
+    }
+
+    /// Run the frame through the decoder and the meter report parser.
+    pub fn replay(&self) -> Result<MeterData, String> {
+        parse_meter_report(&self.decode()).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_roundtrip() {
+        let mut capture = Capture::new(16, 1087372);
+        capture.registers = vec![0x0D, 0x2E, 0x06];
+        capture.push_frame(
+            FrameKind::Ack,
+            LinkQuality {
+                rssi_dbm: -80,
+                lqi: 12,
+                freq_est: 3,
+            },
+            &[0x00, 0xFF, 0xF0, 0x00],
+            3,
+        );
+
+        let dir = std::env::temp_dir().join(format!("everblu-capture-{}", std::process::id()));
+        let path = capture.save(&dir).unwrap();
+        let loaded = Capture::load(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded.serial, 1087372);
+        assert_eq!(loaded.registers, vec![0x0D, 0x2E, 0x06]);
+        assert_eq!(loaded.frames.len(), 1);
+        assert_eq!(loaded.frames[0].kind, FrameKind::Ack);
+        assert_eq!(loaded.frames[0].link_quality.rssi_dbm, -80);
+        assert_eq!(loaded.frames[0].rx_buffer, vec![0x00, 0xFF, 0xF0]);
+    }
+}
@@ -1,79 +1,220 @@
+//! Driver for the CC1101 transceiver, over any [`RadioBus`].
+
+use crate::bus::{BusError, Gdo, RadioBus};
 use crate::cc1101_const::*;
-use crate::radian::{decode_4bitpbit_serial, make_radian_master_req};
+use crate::exchange::{ExchangeError, LinkQuality, RadianLink, ReceivedFrame};
+use crate::profile::{frequency_registers, RegisterProfile};
+use crate::radian::{decode_oversampled_into, rx_window_len};
 use log::debug;
-use rppal::{gpio, spi};
-use serde::Serialize;
-use std::sync::atomic::{AtomicU8, Ordering};
-
-static PA: [u8; 8] = [0x60, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
-
-static CC1101_STATUS_STATE: AtomicU8 = AtomicU8::new(0); // TODO we can probably do better than having a shared mutable state
-static CC1101_STATUS_FIFO_FREEBYTE: AtomicU8 = AtomicU8::new(0);
-static CC1101_STATUS_FIFO_READBYTE: AtomicU8 = AtomicU8::new(0);
-
-#[derive(Debug, Serialize)]
-pub struct MeterData {
-    pub liters: i32,
-    pub reads_counter: i32, // how many times the meter has been readed
-    pub battery_left: i32,  //in months
-    pub time_start: i32,    // like 8am
-    pub time_end: i32,      // like 4pm
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+// FIFOTHR=0x47: GDO2 signals 33 bytes in the TX FIFO and 32 bytes in the RX FIFO
+const FIFO_THRESHOLD: usize = 32;
+// 32 bytes at 2.4 kbps take 107 ms to leave the TX FIFO, at 9.6 kbps 27 ms to fill the RX FIFO
+const TX_FIFO_DRAIN_TMO: Duration = Duration::from_millis(250);
+const RX_FIFO_FILL_TMO: Duration = Duration::from_millis(40);
+// Clear channel checks before giving up on a transmission, backing off 50, 100, 150 then 200 ms
+const LBT_ATTEMPTS: u32 = 5;
+const LBT_BACKOFF_MS: u32 = 50;
+// RSSI readings averaged at each frequency of a scan
+const SCAN_SAMPLES: i32 = 8;
+// Longest known RADIAN frame, the meter data frame
+const MAX_FRAME_SIZE: i32 = 0x7C;
+
+/// Main radio control state machine state, as reported in the chip status byte.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ChipState {
+    #[default]
+    Idle,
+    Rx,
+    Tx,
+    FsTxOn,
+    Calibrate,
+    Settling,
+    RxFifoOverflow,
+    TxFifoUnderflow,
+}
+
+/// Chip status byte, clocked out by the CC1101 on every SPI header and data byte.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ChipStatus {
+    pub ready: bool,
+    pub state: ChipState,
+    // free bytes in the TX FIFO after a write, bytes in the RX FIFO after a read, 15 meaning 15 or more
+    pub fifo_bytes_available: u8,
 }
 
+impl From<u8> for ChipStatus {
+    fn from(status: u8) -> Self {
+        let state = match (status >> 4) & 0x07 {
+            0 => ChipState::Idle,
+            1 => ChipState::Rx,
+            2 => ChipState::Tx,
+            3 => ChipState::FsTxOn,
+            4 => ChipState::Calibrate,
+            5 => ChipState::Settling,
+            6 => ChipState::RxFifoOverflow,
+            _ => ChipState::TxFifoUnderflow,
+        };
+        ChipStatus {
+            ready: status & 0x80 == 0,
+            state,
+            fifo_bytes_available: status & 0x0F,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RadioError {
+    BusError(BusError),
+    NotDetected { partnum: u8, version: u8 },
+    Miswired(String),
+}
+
+impl std::fmt::Display for RadioError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RadioError::BusError(e) => write!(f, "{}", e),
+            RadioError::NotDetected { partnum, version } => write!(
+                f,
+                "Radio not detected: CC1101 PARTNUM=0x{:02X} VERSION=0x{:02X}, check the SPI wiring",
+                partnum, version
+            ),
+            RadioError::Miswired(e) => write!(f, "Radio miswired: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RadioError {}
+
+impl From<BusError> for RadioError {
+    fn from(err: BusError) -> Self {
+        RadioError::BusError(err)
+    }
+}
+
+/// CC1101 driver: register profile, RADIAN transmit and receive, power down between exchanges.
 pub struct CC1101 {
-    gdo0: gpio::InputPin,
-    // gdo2: gpio::InputPin,
-    spi: spi::Spi,
+    bus: Box<dyn RadioBus>,
+    status: Cell<ChipStatus>, // from the last SPI transaction
+    profile: RegisterProfile,
+    asleep: Cell<bool>,
 }
 
 impl CC1101 {
-    pub fn new() -> Self {
-        // Initialize GPIO
-        debug!("Initializing GPIO");
-        let gpio = gpio::Gpio::new().expect("Failed to initialize GPIO");
-        let gdo0 = gpio.get(17).unwrap().into_input(); //pin 0 (GDO0) = BCM GPIO 17 (physical pin 11)
-        let _gdo2 = gpio.get(27).unwrap().into_input(); //pin 2 (GDO2) = BCM GPIO 27 (physical pin 13) TODO never read (is it used ?)
-
-        // to use SPI pi@MinePi ~ $ gpio unload spi  then gpio load spi
-        // sinon pas de MOSI ni pas de CSn , buffer de 4kB
-        let speed = 100000u32;
-        let spi = spi::Spi::new(
-            spi::Bus::Spi0,
-            spi::SlaveSelect::Ss0,
-            speed,
-            spi::Mode::Mode0,
-        )
-        .unwrap();
-
+    /// Reset and configure the CC1101, failing if the self-test does not pass.
+    pub fn new(bus: Box<dyn RadioBus>, profile: RegisterProfile) -> Result<Self, RadioError> {
         let cc1101 = CC1101 {
-            gdo0,
-            // gdo2,
-            spi,
+            bus,
+            status: Cell::new(ChipStatus::default()),
+            profile,
+            asleep: Cell::new(true), // possibly left powered down by a previous run
         };
-        cc1101.reset();
-        Self::delay(1);
-        cc1101.cc1101_configure_rf_0();
+        cc1101.selftest()?;
 
         debug!("{}", cc1101.version());
         debug!("{}", cc1101.registers_settings());
 
-        cc1101
+        Ok(cc1101)
+    }
+
+    /// Check the chip answers as a CC1101, keeps every register written and reaches RX.
+    /// Leaves it reset and configured.
+    pub fn selftest(&self) -> Result<(), RadioError> {
+        self.wake_up();
+
+        // MISO stuck low or floating high reads 0x00 or 0xFF everywhere
+        let partnum = self.hal_rf_read_reg(PARTNUM_ADDR);
+        let version = self.hal_rf_read_reg(VERSION_ADDR);
+        if partnum != 0x00 || version == 0x00 || version == 0xFF {
+            return Err(RadioError::NotDetected { partnum, version });
+        }
+
+        self.reset();
+        Self::delay(1);
+        self.cc1101_configure_rf_0();
+
+        // read back before entering RX, which recalibrates and overwrites FSCAL3..0
+        let registers = self.read_registers();
+        for &(reg_addr, value) in &self.profile.base {
+            let read = registers[reg_addr as usize];
+            if read != value {
+                return Err(RadioError::Miswired(format!(
+                    "register 0x{:02X} reads 0x{:02X} instead of 0x{:02X}",
+                    reg_addr, read, value
+                )));
+            }
+        }
+        let mut patable = [0u8; 8];
+        self.spi_read_burst_reg(PATABLE_ADDR, &mut patable);
+        if patable != self.profile.patable {
+            return Err(RadioError::Miswired(format!(
+                "PATABLE reads {:02X?} instead of {:02X?}",
+                patable, self.profile.patable
+            )));
+        }
+
+        // calibration then RX take about 1 ms, unless the crystal does not run
+        self.cmd(SIDLE);
+        self.cmd(SRX);
+        let deadline = Instant::now() + Duration::from_millis(10);
+        let mut marcstate = self.hal_rf_read_reg(MARCSTATE_ADDR) & 0x1F;
+        while !(0x0D..=0x0F).contains(&marcstate) && Instant::now() < deadline {
+            marcstate = self.hal_rf_read_reg(MARCSTATE_ADDR) & 0x1F;
+        }
+        self.cmd(SIDLE);
+        self.cmd(SFRX);
+        if !(0x0D..=0x0F).contains(&marcstate) {
+            return Err(RadioError::Miswired(format!(
+                "MARCSTATE stuck at 0x{:02X} instead of reaching RX",
+                marcstate
+            )));
+        }
+
+        debug!(
+            "CC1101 self-test passed: PARTNUM=0x{:02X} VERSION=0x{:02X}",
+            partnum, version
+        );
+        Ok(())
+    }
+
+    /// Chip status returned by the last SPI transaction.
+    pub fn status(&self) -> ChipStatus {
+        self.status.get()
+    }
+
+    /// Register profile written at each transmit and receive phase.
+    pub fn profile(&self) -> &RegisterProfile {
+        &self.profile
+    }
+
+    /// Switch to another register profile, written right away unless powered down.
+    pub fn set_profile(&mut self, profile: RegisterProfile) {
+        self.profile = profile;
+        if !self.asleep.get() {
+            self.cc1101_configure_rf_0();
+        }
+    }
+
+    fn update_status(&self, status: u8) -> ChipStatus {
+        let status = ChipStatus::from(status);
+        self.status.set(status);
+        status
     }
 
     //------------------[write register]--------------------------------
-    fn hal_rf_write_reg(&self, reg_addr: u8, value: u8) {
+    fn hal_rf_write_reg(&self, reg_addr: u8, value: u8) -> ChipStatus {
         let mut tbuf = [reg_addr | WRITE_SINGLE_BYTE, value];
         self.data_rw(&mut tbuf);
-        CC1101_STATUS_FIFO_FREEBYTE.store(tbuf[1] & 0x0F, Ordering::Relaxed);
-        CC1101_STATUS_STATE.store((tbuf[0] >> 4) & 0x0F, Ordering::Relaxed);
+        self.update_status(tbuf[1])
     }
 
     fn hal_rf_read_reg(&self, spi_instr: u8) -> u8 {
         let mut rbuf = [spi_instr | READ_SINGLE_BYTE, 0];
         //errata Section 3. You have to make sure that you read the same value of the register twice in a row before you evaluate it otherwise you might read a value that is a mix of 2 state values.
         self.data_rw(&mut rbuf);
-        CC1101_STATUS_FIFO_READBYTE.store(rbuf[0] & 0x0F, Ordering::Relaxed);
-        CC1101_STATUS_STATE.store((rbuf[0] >> 4) & 0x0F, Ordering::Relaxed);
+        self.update_status(rbuf[0]);
         rbuf[1]
     }
 
@@ -83,24 +224,22 @@ impl CC1101 {
         rbuf[0] = spi_instr | READ_BURST;
         self.data_rw(&mut rbuf);
         buffer.copy_from_slice(&rbuf[1..]);
-        CC1101_STATUS_FIFO_READBYTE.store(rbuf[0] & 0x0F, Ordering::Relaxed);
-        CC1101_STATUS_STATE.store((rbuf[0] >> 4) & 0x0F, Ordering::Relaxed);
+        self.update_status(rbuf[0]);
     }
 
-    fn spi_write_burst_reg(&self, spi_instr: u8, p_arr: &[u8], len: u8) {
+    fn spi_write_burst_reg(&self, spi_instr: u8, p_arr: &[u8], len: u8) -> ChipStatus {
         let mut tbuf = vec![0u8; (len + 1) as usize];
         tbuf[0] = spi_instr | WRITE_BURST;
         tbuf[1..].copy_from_slice(p_arr);
         self.data_rw(&mut tbuf);
-        CC1101_STATUS_FIFO_FREEBYTE.store(tbuf[len as usize] & 0x0F, Ordering::Relaxed);
-        CC1101_STATUS_STATE.store((tbuf[len as usize] >> 4) & 0x0F, Ordering::Relaxed);
+        self.update_status(tbuf[len as usize])
     }
 
-    fn cmd(&self, spi_instr: u8) {
+    fn cmd(&self, spi_instr: u8) -> ChipStatus {
         let mut tbuf: [u8; 1] = [0];
         tbuf[0] = spi_instr | WRITE_SINGLE_BYTE;
         self.data_rw(&mut tbuf);
-        CC1101_STATUS_STATE.store((tbuf[0] >> 4) & 0x0F, Ordering::Relaxed);
+        self.update_status(tbuf[0])
     }
 
     //---------------[CC1100 reset functions "200us"]-----------------------
@@ -128,68 +267,40 @@ impl CC1101 {
     }
 
     fn cc1101_configure_rf_0(&self) {
-        //
-        // Rf settings for CC1101
-        //
-        self.hal_rf_write_reg(IOCFG2, 0x0D); //GDO2 Output Pin Configuration : Serial Data Output
-        self.hal_rf_write_reg(IOCFG0, 0x06); //GDO0 Output Pin Configuration : Asserts when sync word has been sent / received, and de-asserts at the end of the packet.
-        self.hal_rf_write_reg(FIFOTHR, 0x47); //0x4? adc with bandwith< 325khz
-        self.hal_rf_write_reg(SYNC1, 0x55); //01010101
-        self.hal_rf_write_reg(SYNC0, 0x00); //00000000
-
-        //self.hal_rf_write_reg(PKTCTRL1,0x80);//Preamble quality estimator threshold=16  ; APPEND_STATUS=0; no addr check
-        self.hal_rf_write_reg(PKTCTRL1, 0x00); //Preamble quality estimator threshold=0   ; APPEND_STATUS=0; no addr check
-        self.hal_rf_write_reg(PKTCTRL0, 0x00); //fix length , no CRC
-        self.hal_rf_write_reg(FSCTRL1, 0x08); //Frequency Synthesizer Control
-
-        self.hal_rf_write_reg(FREQ2, 0x10); //Frequency Control Word, High Byte  Base frequency = 433.82
-        self.hal_rf_write_reg(FREQ1, 0xAF); //Frequency Control Word, Middle Byte
-        self.hal_rf_write_reg(FREQ0, 0x75); //Frequency Control Word, Low Byte la fréquence reel etait 433.790 (centre)
-                                            //self.hal_rf_write_reg(FREQ0,0xC1); //Frequency Control Word, Low Byte rasmobo 814 824 (KO) ; minepi 810 820 (OK)
-                                            //self.hal_rf_write_reg(FREQ0,0x9B); //rasmobo 808.5  -16  pour -38
-                                            //self.hal_rf_write_reg(FREQ0,0xB7);   //rasmobo 810 819.5 OK
-                                            //mon compteur F1 : 433809500  F2 : 433820000   deviation +-5.25khz depuis 433.81475M
-
-        self.hal_rf_write_reg(MDMCFG4, 0xF6); //Modem Configuration   RX filter BW = 58Khz
-        self.hal_rf_write_reg(MDMCFG3, 0x83); //Modem Configuration   26M*((256+83h)*2^6)/2^28 = 2.4kbps
-        self.hal_rf_write_reg(MDMCFG2, 0x02); //Modem Configuration   2-FSK;  no Manchester ; 16/16 sync word bits detected
-        self.hal_rf_write_reg(MDMCFG1, 0x00); //Modem Configuration num preamble 2=>0 , Channel spacing_exp
-        self.hal_rf_write_reg(MDMCFG0, 0x00); /*# MDMCFG0 Channel spacing = 25Khz*/
-        self.hal_rf_write_reg(DEVIATN, 0x15); //5.157471khz
-                                              //self.hal_rf_write_reg(MCSM1,0x0F);   //CCA always ; default mode RX
-        self.hal_rf_write_reg(MCSM1, 0x00); //CCA always ; default mode IDLE
-        self.hal_rf_write_reg(MCSM0, 0x18); //Main Radio Control State Machine Configuration
-        self.hal_rf_write_reg(FOCCFG, 0x1D); //Frequency Offset Compensation Configuration
-        self.hal_rf_write_reg(BSCFG, 0x1C); //Bit Synchronization Configuration
-        self.hal_rf_write_reg(AGCCTRL2, 0xC7); //AGC Control
-        self.hal_rf_write_reg(AGCCTRL1, 0x00); //AGC Control
-        self.hal_rf_write_reg(AGCCTRL0, 0xB2); //AGC Control
-        self.hal_rf_write_reg(WORCTRL, 0xFB); //Wake On Radio Control
-        self.hal_rf_write_reg(FREND1, 0xB6); //Front End RX Configuration
-        self.hal_rf_write_reg(FSCAL3, 0xE9); //Frequency Synthesizer Calibration
-        self.hal_rf_write_reg(FSCAL2, 0x2A); //Frequency Synthesizer Calibration
-        self.hal_rf_write_reg(FSCAL1, 0x00); //Frequency Synthesizer Calibration
-        self.hal_rf_write_reg(FSCAL0, 0x1F); //Frequency Synthesizer Calibration
-        self.hal_rf_write_reg(TEST2, 0x81); //Various Test Settings link to adc retention
-        self.hal_rf_write_reg(TEST1, 0x35); //Various Test Settings link to adc retention
-        self.hal_rf_write_reg(TEST0, 0x09); //Various Test Settings link to adc retention
-
-        self.spi_write_burst_reg(PATABLE_ADDR, &PA, 8);
-    }
-
-    fn read_gdo0(&self) -> gpio::Level {
-        self.gdo0.read()
-    }
-
-    // fn read_gdo2() -> gpio::Level{
-    //    GDO2.get().unwrap().lock().unwrap().read()
-    // }
+        self.write_registers(&self.profile.base);
+        self.spi_write_burst_reg(PATABLE_ADDR, &self.profile.patable, 8);
+    }
 
-    fn data_rw(&self, data: &mut [u8]) -> i32 {
-        // copy data to be written
-        let write = data.to_vec();
+    // Registers are kept in SLEEP, but for TEST2..0 and the PATABLE beyond index 0.
+    // Leaving IDLE recalibrates by itself (MCSM0 FS_AUTOCAL).
+    fn wake_up(&self) {
+        if !self.asleep.get() {
+            return;
+        }
+        // CSn going low wakes the chip up, its crystal is stable well within 1 ms
+        self.cmd(SNOP);
+        Self::delay(1);
+        self.asleep.set(false);
+        self.cc1101_configure_rf_0();
+    }
+
+    fn write_registers(&self, registers: &[(u8, u8)]) {
+        for &(reg_addr, value) in registers {
+            self.hal_rf_write_reg(reg_addr, value);
+        }
+    }
 
-        match self.spi.transfer(data, &write) {
+    // `false` once `deadline` has passed without `gdo` reaching the level
+    fn wait_gdo(&self, gdo: Gdo, high: bool, deadline: Instant) -> bool {
+        self.bus.wait_gdo(
+            gdo,
+            high,
+            deadline.saturating_duration_since(Instant::now()),
+        )
+    }
+
+    fn data_rw(&self, data: &mut [u8]) -> i32 {
+        match self.bus.transfer(data) {
             Ok(_) => 0,
             Err(e) => {
                 eprintln!("SPI transfer failed: {}", e);
@@ -222,6 +333,16 @@ impl CC1101 {
         }
     }
 
+    // RSSI heard on the channel with the receive settings
+    fn channel_rssi_dbm(&self) -> i8 {
+        self.cc1101_rec_mode();
+        Self::delay(2); // RSSI is valid a few bit periods after entering RX
+        let rssi_dbm = Self::rssi_convert2dbm(self.hal_rf_read_reg(RSSI_ADDR));
+        self.cmd(SIDLE);
+        self.cmd(SFRX);
+        rssi_dbm
+    }
+
     fn version(&self) -> String {
         format!(
             r#"
@@ -233,11 +354,17 @@ impl CC1101 {
         )
     }
 
+    /// Values of the 47 configuration registers, IOCFG2 to TEST0.
+    pub fn read_registers(&self) -> [u8; 47] {
+        let mut config_reg: [u8; 47] = [0; 47]; //47 registers
+        self.spi_read_burst_reg(0, &mut config_reg); //reads all 47 config register from cc1100	"359.63us"
+        config_reg
+    }
+
     fn registers_settings(&self) -> String {
-        let mut config_reg_verify: [u8; 47] = [0; 47]; //47 registers
+        let config_reg_verify = self.read_registers();
         let mut patable_verify: [u8; 8] = [0; 8];
 
-        self.spi_read_burst_reg(0, &mut config_reg_verify); //reads all 47 config register from cc1100	"359.63us"
         self.spi_read_burst_reg(PATABLE_ADDR, &mut patable_verify); //reads output power settings from cc1100	"104us"
 
         format!(
@@ -258,298 +385,375 @@ impl CC1101 {
         )
     }
 
-    fn _is_look_like_radian_frame(buffer: &[u8]) -> bool {
-        for &byte in buffer {
-            if byte == 0xFF {
-                return true;
-            }
-        }
-        false
-    }
-
-    fn parse_meter_report(decoded_buffer: &[u8]) -> Result<MeterData, String> {
-        let size = decoded_buffer.len();
-        let mut data = MeterData {
-            liters: 0,
-            reads_counter: 0,
-            battery_left: 0,
-            time_start: 0,
-            time_end: 0,
-        };
-
-        if size < 30 {
-            return Err(format!(
-                "Failed to read meter data, decode_buffer size {} is < 30",
-                size
-            ));
-        }
-        if size >= 30 {
-            let b18 = decoded_buffer[18] as i32;
-            let b19 = decoded_buffer[19] as i32;
-            let b20 = decoded_buffer[20] as i32;
-            let b21 = decoded_buffer[21] as i32;
-            data.liters = b18 + b19 * 256 + b20 * 65536 + b21 * 16777216;
-        }
-        if size >= 48 {
-            data.reads_counter = decoded_buffer[48] as i32;
-            data.battery_left = decoded_buffer[31] as i32;
-            data.time_start = decoded_buffer[44] as i32;
-            data.time_end = decoded_buffer[45] as i32;
+    /*
+    search for 0101010101010000b sync pattern then change data rate in order to get 4bit per bit
+    search for end of sync pattern with start bit 1111111111110000b
+    without a size, the frame is read up to the length its first byte declares
+    */
+    fn receive_radian_frame(
+        &self,
+        size_byte: Option<i32>,
+        sync_tmo_ms: i32,
+        rx_buffer: &mut [u8],
+    ) -> ReceivedFrame {
+        let window = rx_window_len(size_byte.unwrap_or(MAX_FRAME_SIZE).max(0) as usize);
+        if window > rx_buffer.len() {
+            debug!("buffer too small");
+            return ReceivedFrame::default();
         }
 
-        Ok(data)
-    }
-
-    // Check if Packet is received
-    fn _check_packet_received(&self) -> bool {
-        let mut rx_buffer: [u8; 100] = [0; 100];
-        let mut l_nb_byte: u8;
-        let mut pkt_len: u8 = 0;
-
-        if self.read_gdo0() == gpio::Level::High {
-            // get RF info at beginning of the frame
-            while self.read_gdo0() == gpio::Level::High {
-                Self::delay(5); // wait for some byte received
-                l_nb_byte = self.hal_rf_read_reg(RXBYTES_ADDR) & RXBYTES_MASK;
-                if l_nb_byte != 0 && (pkt_len + l_nb_byte) < 100 {
-                    self.spi_read_burst_reg(
-                        RX_FIFO_ADDR,
-                        &mut rx_buffer[pkt_len as usize..(pkt_len + l_nb_byte) as usize],
-                    );
-                    pkt_len += l_nb_byte;
-                }
-            }
-
-            if Self::_is_look_like_radian_frame(&rx_buffer[..pkt_len as usize]) {
-                debug!(
-                    "bytes={} rssi={} lqi={} F_est={}",
-                    pkt_len,
-                    Self::rssi_convert2dbm(self.hal_rf_read_reg(RSSI_ADDR)),
-                    self.hal_rf_read_reg(LQI_ADDR),
-                    self.hal_rf_read_reg(FREQEST_ADDR)
-                );
-                debug!("{:02X?}", rx_buffer);
-            } else {
-                debug!(".")
-            }
+        self.wake_up();
+        let frame = self.receive_phases(size_byte, sync_tmo_ms, rx_buffer);
 
-            return true;
-        }
-        false
-    }
+        // stop reception and restore default reg, whichever phase the frame was lost in
+        self.hal_rf_write_reg(IOCFG2, 0x01);
+        self.cmd(SIDLE);
+        self.cmd(SFRX);
+        self.write_registers(&self.profile.restore());
 
-    fn _wait_for_packet(&self, milliseconds: i32) -> bool {
-        for _ in 0..milliseconds {
-            Self::delay(1); // in ms
-            if self._check_packet_received() {
-                return true;
-            }
-        }
-        false
+        frame
     }
 
-    /*
-    search for 0101010101010000b sync pattern then change data rate in order to get 4bit per bit
-    search for end of sync pattern with start bit 1111111111110000b
-    */
-    fn receive_radian_frame(&self, size_byte: i32, rx_tmo_ms: i32, rx_buffer: &mut [u8]) -> u16 {
-        let mut l_byte_in_rx: u8 = 0;
+    fn receive_phases(
+        &self,
+        size_byte: Option<i32>,
+        sync_tmo_ms: i32,
+        rx_buffer: &mut [u8],
+    ) -> ReceivedFrame {
+        let mut l_byte_in_rx: u8;
         let mut l_total_byte: u16 = 0;
-        let l_radian_frame_size_byte: u16 = ((size_byte * (8 + 3)) / 8 + 1) as u16;
-        let mut l_tmo: i32 = 0;
-
-        if (l_radian_frame_size_byte * 4) as i32 > rx_buffer.len() as i32 {
-            debug!("buffer too small");
-            return 0;
-        }
+        let mut sized = size_byte.is_some();
+        let size_byte = size_byte.unwrap_or(MAX_FRAME_SIZE);
+        let mut l_frame_end = rx_window_len(size_byte.max(0) as usize) as u16;
+        let start = Instant::now();
+        let sync_deadline = start + Duration::from_millis(sync_tmo_ms.max(0) as u64);
+        let mut frame = ReceivedFrame::default();
 
         self.cmd(SFRX);
-        self.hal_rf_write_reg(MCSM1, 0x0F); // CCA always ; default mode RX
-        self.hal_rf_write_reg(MDMCFG2, 0x02); // Modem Configuration   2-FSK;  no Manchester ; 16/16 sync word bits detected
-                                              // configure to receive beginning of sync pattern
-        self.hal_rf_write_reg(SYNC1, 0x55); // 01010101
-        self.hal_rf_write_reg(SYNC0, 0x50); // 01010000
-        self.hal_rf_write_reg(MDMCFG4, 0xF6); // Modem Configuration   RX filter BW = 58Khz
-        self.hal_rf_write_reg(MDMCFG3, 0x83); // Modem Configuration   26M*((256+83h)*2^6)/2^28 = 2.4kbps
-        self.hal_rf_write_reg(PKTLEN, 1); // just one byte of synch pattern
+        self.write_registers(&self.profile.sync_search);
         self.cc1101_rec_mode();
 
-        while self.read_gdo0() == gpio::Level::Low && l_tmo < rx_tmo_ms {
-            Self::delay(1);
-            l_tmo += 1;
-        }
-        if l_tmo < rx_tmo_ms {
+        if self.wait_gdo(Gdo::Gdo0, true, sync_deadline) {
             debug!("GDO0!");
         } else {
-            return 0;
+            return frame;
         }
+        let sync_at = Instant::now();
+        frame.sync_ms = (sync_at - start).as_millis() as u32;
+
+        // the frame then lasts the rest of its preamble and 11 bits per byte at 2.4 kbps
+        let frame_ms = 30 + size_byte.max(0) as u64 * 11 * 1000 / 2400;
+        let deadline = sync_at + Duration::from_millis(frame_ms * 5 / 4);
 
-        while l_byte_in_rx == 0 && l_tmo < rx_tmo_ms {
-            Self::delay(5);
-            l_tmo += 5; // wait for some byte received
+        // GDO2 asserts at the end of the one byte packet
+        if self.wait_gdo(Gdo::Gdo2, true, deadline) {
             l_byte_in_rx = self.hal_rf_read_reg(RXBYTES_ADDR) & RXBYTES_MASK;
-            if l_byte_in_rx != 0 {
-                self.spi_read_burst_reg(RX_FIFO_ADDR, &mut rx_buffer[..l_byte_in_rx as usize]); // Pull data
-                debug!("{:02X?}", rx_buffer)
-            }
-        }
-        if l_tmo < rx_tmo_ms {
+            self.spi_read_burst_reg(RX_FIFO_ADDR, &mut rx_buffer[..l_byte_in_rx as usize]); // Pull data
+            debug!("{:02X?}", &rx_buffer[..l_byte_in_rx as usize]);
             debug!("1st synch received")
         } else {
-            return 0;
+            return frame;
         }
 
+        frame.link_quality = LinkQuality {
+            rssi_dbm: Self::rssi_convert2dbm(self.hal_rf_read_reg(RSSI_ADDR)),
+            lqi: self.hal_rf_read_reg(LQI_ADDR),
+            freq_est: self.hal_rf_read_reg(FREQEST_ADDR),
+        };
         debug!(
             "rssi={} lqi={} F_est={}",
-            Self::rssi_convert2dbm(self.hal_rf_read_reg(RSSI_ADDR)),
-            self.hal_rf_read_reg(LQI_ADDR),
-            self.hal_rf_read_reg(FREQEST_ADDR)
+            frame.link_quality.rssi_dbm, frame.link_quality.lqi, frame.link_quality.freq_est
         );
 
-        self.hal_rf_write_reg(SYNC1, 0xFF); // 11111111
-        self.hal_rf_write_reg(SYNC0, 0xF0); // 11110000 la fin du synch pattern et le bit de start
-        self.hal_rf_write_reg(MDMCFG4, 0xF8); // Modem Configuration   RX filter BW = 58Khz
-        self.hal_rf_write_reg(MDMCFG3, 0x83); // Modem Configuration   26M*((256+83h)*2^8)/2^28 = 9.59kbps
-        self.hal_rf_write_reg(PKTCTRL0, 0x02); // infinite packet len
+        self.write_registers(&self.profile.data);
+        self.hal_rf_write_reg(IOCFG2, 0x00); // GDO2 asserts when the RX FIFO reaches the threshold, de-asserts below it
         self.cmd(SFRX);
         self.cc1101_rec_mode();
 
-        l_byte_in_rx = 1;
-        while self.read_gdo0() == gpio::Level::Low && l_tmo < rx_tmo_ms {
-            Self::delay(1);
-            l_tmo += 1;
-        }
-        if l_tmo < rx_tmo_ms {
+        if self.wait_gdo(Gdo::Gdo0, true, deadline) {
             debug!("GDO0!");
         } else {
-            return 0;
+            return frame;
         }
 
-        while l_byte_in_rx > 0 && l_total_byte < (l_radian_frame_size_byte * 4) && l_tmo < rx_tmo_ms
-        {
-            Self::delay(5);
-            l_tmo += 5; // wait for some byte received
+        while l_total_byte < l_frame_end && Instant::now() < deadline {
+            // the tail of the frame never reaches the threshold, it is pulled once the wait times out
+            let chunk_deadline = deadline.min(Instant::now() + RX_FIFO_FILL_TMO);
+            self.wait_gdo(Gdo::Gdo2, true, chunk_deadline);
             l_byte_in_rx = self.hal_rf_read_reg(RXBYTES_ADDR) & RXBYTES_MASK;
-            if l_byte_in_rx != 0 {
-                let start = l_total_byte as usize;
-                let end = start + l_byte_in_rx as usize;
-                self.spi_read_burst_reg(RX_FIFO_ADDR, &mut rx_buffer[start..end]); // Pull data
-                l_total_byte += l_byte_in_rx as u16;
+            if l_byte_in_rx == 0 {
+                break; // FIFO dry: the frame is over
+            }
+            let start = l_total_byte as usize;
+            let end = (start + l_byte_in_rx as usize).min(l_frame_end as usize);
+            self.spi_read_burst_reg(RX_FIFO_ADDR, &mut rx_buffer[start..end]); // Pull data
+            l_total_byte = end as u16;
+
+            // the receiver keeps sampling noise after a short frame, the FIFO never runs dry
+            if !sized {
+                let mut length = [0u8; 1];
+                let stats = decode_oversampled_into(&rx_buffer[..end], &mut length);
+                if stats.len == 1 && !stats.truncated {
+                    debug!("frame of {} bytes", length[0]);
+                    l_frame_end = l_frame_end.min(rx_window_len(length[0] as usize) as u16);
+                    sized = true;
+                }
             }
         }
-        if l_tmo < rx_tmo_ms {
-            debug!("frame received");
-        } else {
-            return 0;
+        frame.byte_count = l_total_byte;
+        frame.frame_ms = sync_at.elapsed().as_millis() as u32;
+        if l_total_byte < l_frame_end && Instant::now() >= deadline {
+            return frame;
         }
+        debug!("frame received");
 
-        // stop reception
-        self.cmd(SFRX);
-        self.cmd(SIDLE);
+        frame.complete = true;
+        frame
+    }
+
+    /// Listen at the RADIAN settings for any frame, whoever sends it.
+    /// Returns the raw oversampled buffer, or None if no frame started within the timeout.
+    pub fn sniff_frame(&self, sync_tmo_ms: i32) -> Option<(Vec<u8>, LinkQuality)> {
+        let mut rx_buffer: [u8; 1000] = [0; 1000];
+
+        let frame = self.receive_radian_frame(None, sync_tmo_ms, &mut rx_buffer);
+        if frame.byte_count == 0 {
+            return None;
+        }
 
-        // restore default reg
-        self.hal_rf_write_reg(MDMCFG4, 0xF6); // Modem Configuration   RX filter BW = 58Khz
-        self.hal_rf_write_reg(MDMCFG3, 0x83); // Modem Configuration   26M*((256+83h)*2^6)/2^28 = 2.4kbps
-        self.hal_rf_write_reg(PKTCTRL0, 0x00); // fix packet len
-        self.hal_rf_write_reg(PKTLEN, 38);
-        self.hal_rf_write_reg(SYNC1, 0x55); // 01010101
-        self.hal_rf_write_reg(SYNC0, 0x00); // 00000000
+        debug!("{:02X?}", &rx_buffer[..frame.byte_count as usize]);
+        Some((
+            rx_buffer[..frame.byte_count as usize].to_vec(),
+            frame.link_quality,
+        ))
+    }
 
-        l_total_byte
+    /// Carrier frequency of the register profile, in Hz.
+    pub fn carrier_hz(&self) -> u32 {
+        self.profile.frequency_hz()
     }
 
-    /*
-    scenario_releve
-    2s de WUP
-    130ms : trame interrogation de l'outils de reléve   ______------|...............-----
-    43ms de bruit
-    34ms 0101...01
-    14.25ms 000...000
-    14ms 1111...11111
-    83.5ms de data acquitement
-    50ms de 111111
-    34ms 0101...01
-    14.25ms 000...000
-    14ms 1111...11111
-    582ms de data avec l'index
-
-    l'outils de reléve doit normalement acquité
-    */
-    pub fn get_meter_data(&self, year: u8, serial: u32) -> Result<MeterData, String> {
-        // let mut marcstate: u8 = 0xFF;
-        let wupbuffer: [u8; 8] = [0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55];
-        let mut wup2send: u8 = 77;
-        let mut tmo: u16 = 0;
-        let mut rx_buffer: [u8; 1000] = [0; 1000];
+    /// Average RSSI heard at each of `frequencies_hz` with the receive settings, in dBm.
+    /// The carrier of the profile is restored afterwards.
+    pub fn scan(&self, frequencies_hz: &[u32]) -> Vec<i8> {
+        self.wake_up();
+        let levels = frequencies_hz
+            .iter()
+            .map(|&frequency_hz| {
+                // in IDLE after each reading, entering RX recalibrates the synthesizer
+                self.write_registers(&frequency_registers(frequency_hz));
+                let total: i32 = (0..SCAN_SAMPLES)
+                    .map(|_| self.channel_rssi_dbm() as i32)
+                    .sum();
+                (total / SCAN_SAMPLES) as i8
+            })
+            .collect();
+        self.write_registers(&frequency_registers(self.profile.frequency_hz()));
+        levels
+    }
+}
 
-        // let mut txbuffer: [u8; 100] = [0; 100];
-        let txbuffer = make_radian_master_req(year, serial);
+impl RadianLink for CC1101 {
+    fn transmit(&self, txbuffer: &[u8]) -> Result<(), ExchangeError> {
+        self.wake_up();
 
-        self.hal_rf_write_reg(MDMCFG2, 0x00); // clear MDMCFG2 to do not send preamble and sync
-        self.hal_rf_write_reg(PKTCTRL0, 0x02); // infinite packet len
-        self.spi_write_burst_reg(TX_FIFO_ADDR, &wupbuffer, 8);
-        wup2send -= 1;
+        // listen before talk, backing off while another device is on air
+        let mut attempt = 1;
+        loop {
+            let rssi_dbm = self.channel_rssi_dbm();
+            if rssi_dbm <= self.profile.lbt_threshold_dbm {
+                break;
+            }
+            if attempt == LBT_ATTEMPTS {
+                return Err(ExchangeError::ChannelBusy(rssi_dbm));
+            }
+            debug!("Channel busy at {} dBm, backing off", rssi_dbm);
+            Self::delay(LBT_BACKOFF_MS * attempt);
+            attempt += 1;
+        }
+
+        // WUP of 0x55 at 2.4 kbps (300 bytes per second), followed by the request
+        let mut data = vec![0x55; self.profile.wakeup_ms as usize * 3 / 10];
+        data.extend_from_slice(txbuffer);
+
+        self.write_registers(&self.profile.tx);
+        self.hal_rf_write_reg(IOCFG2, 0x02); // GDO2 asserts when the TX FIFO reaches the threshold, de-asserts below it
+
+        // fill the 64 bytes FIFO before starting, then top it up each time it drains below the threshold
+        let (first, rest) = data.split_at((2 * FIFO_THRESHOLD - 8).min(data.len()));
+        self.spi_write_burst_reg(TX_FIFO_ADDR, first, first.len() as u8);
         self.cmd(STX); // sends the data store into transmit buffer over the air
-        Self::delay(10); // to give time for calibration
-        let marcstate = self.hal_rf_read_reg(MARCSTATE_ADDR); // to update CC1101_status_state
+        let marcstate = self.hal_rf_read_reg(MARCSTATE_ADDR);
         debug!(
-            "MARCSTATE : raw:0x{}  0x{} free_byte:0x{} sts:0x{} sending 2s WUP...",
+            "MARCSTATE : raw:0x{:02X}  0x{:02X} {:?} sending {}ms WUP...",
             marcstate,
             (marcstate & 0x1F),
-            CC1101_STATUS_FIFO_FREEBYTE.load(Ordering::Relaxed),
-            CC1101_STATUS_STATE.load(Ordering::Relaxed)
+            self.status(),
+            self.profile.wakeup_ms
         );
 
-        while CC1101_STATUS_STATE.load(Ordering::Relaxed) == 0x02 && tmo < TX_LOOP_OUT {
-            // in TX
-            if wup2send != 0 {
-                if wup2send < 0xFF {
-                    if CC1101_STATUS_FIFO_FREEBYTE.load(Ordering::Relaxed) <= 10 {
-                        // this give 10+20ms from previous frame : 8*8/2.4k=26.6ms  temps pour envoyer un wupbuffer
-                        Self::delay(20);
-                        tmo += 2;
-                    }
-                    self.spi_write_burst_reg(TX_FIFO_ADDR, &wupbuffer, 8);
-                    wup2send -= 1;
-                }
-            } else {
-                Self::delay(130); // 130ms time to free 39bytes FIFO space
-                self.spi_write_burst_reg(TX_FIFO_ADDR, &txbuffer, 39);
-                debug!("{:02X?}", txbuffer);
-                wup2send = 0xFF;
+        let mut sent = first.len();
+        for chunk in rest.chunks(FIFO_THRESHOLD) {
+            // below the threshold at most 32 bytes are left in the FIFO: the chunk fits
+            if !self.wait_gdo(Gdo::Gdo2, false, Instant::now() + TX_FIFO_DRAIN_TMO) {
+                debug!("TX FIFO not draining");
+                break;
             }
-            Self::delay(10);
-            tmo += 1;
-            self.hal_rf_read_reg(MARCSTATE_ADDR); // read out state of cc1100 to be sure in IDLE and TX is finished this update also CC1101_status_state
+            let status = self.spi_write_burst_reg(TX_FIFO_ADDR, chunk, chunk.len() as u8);
+            if status.state != ChipState::Tx {
+                debug!("TX stopped: {:?}", status);
+                break;
+            }
+            sent += chunk.len();
         }
+        debug!("{:02X?}", txbuffer);
 
-        debug!(
-            "{}ifree_byte:{} sts:{}",
-            tmo,
-            CC1101_STATUS_FIFO_FREEBYTE.load(Ordering::Relaxed),
-            CC1101_STATUS_STATE.load(Ordering::Relaxed)
-        );
+        // the FIFO underflows once the request is on air
+        self.hal_rf_write_reg(IOCFG2, 0x05); // GDO2 asserts when the TX FIFO has underflowed
+        self.wait_gdo(Gdo::Gdo2, true, Instant::now() + TX_FIFO_DRAIN_TMO);
+        let status = self.cmd(SNOP);
+        debug!("{}/{} bytes sent {:?}", sent, data.len(), status);
         self.cmd(SFTX); // flush the Tx_fifo content this clear the status state and put sate machin in IDLE
 
         // end of transition restore default register
-        self.hal_rf_write_reg(MDMCFG2, 0x02); // Modem Configuration   2-FSK;  no Manchester ; 16/16 sync word bits detected
-        self.hal_rf_write_reg(PKTCTRL0, 0x00); // fix packet len
+        self.write_registers(&self.profile.restore());
+        self.hal_rf_write_reg(IOCFG2, 0x01);
+        Ok(())
+    }
+
+    fn receive(&self, size_byte: i32, sync_tmo_ms: i32, rx_buffer: &mut [u8]) -> ReceivedFrame {
+        self.receive_radian_frame(Some(size_byte), sync_tmo_ms, rx_buffer)
+    }
 
-        Self::delay(30); // 43ms de bruit
-                         // 34ms 0101...01  14.25ms 000...000  14ms 1111...11111  83.5ms de data acquitement
-        if self.receive_radian_frame(0x12, 150, &mut rx_buffer) == 0 {
-            debug!("TMO on REC");
+    fn power_down(&self) {
+        if self.asleep.get() {
+            return;
         }
-        Self::delay(30); // 50ms de 111111  , mais on a 7+3ms de printf et xxms calculs
-                         // 34ms 0101...01  14.25ms 000...000  14ms 1111...11111  582ms de data avec l'index
-        let rx_buffer_size = self.receive_radian_frame(0x7C, 700, &mut rx_buffer);
-        if rx_buffer_size != 0 {
-            debug!("{:02X?}", rx_buffer);
-
-            let meter_data = decode_4bitpbit_serial(&rx_buffer, rx_buffer_size);
-            Self::parse_meter_report(&meter_data)
-        } else {
-            Err("TMO on REC".to_string())
+        self.cmd(SIDLE);
+        // GDO0 and GDO2 driven low rather than left toggling
+        self.hal_rf_write_reg(IOCFG2, 0x2F);
+        self.hal_rf_write_reg(IOCFG0, 0x2F);
+        self.cmd(SPWD); // enters SLEEP as CSn goes high
+        self.asleep.set(true);
+        debug!("CC1101 powered down");
+    }
+
+    fn registers(&self) -> Vec<u8> {
+        self.wake_up();
+        self.read_registers().to_vec()
+    }
+}
+
+impl Drop for CC1101 {
+    fn drop(&mut self) {
+        self.power_down();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    // Configuration registers and PATABLE of a CC1101 that hears what `gdo0` and `gdo2` script,
+    // the registers in place at each GDO0 wait are recorded
+    #[derive(Default)]
+    struct FakeChip {
+        registers: Vec<u8>,
+        patable: [u8; 8],
+        gdo0: VecDeque<bool>,
+        gdo2: VecDeque<bool>,
+        gdo0_waits: Vec<Vec<u8>>,
+    }
+
+    struct FakeBus(Rc<RefCell<FakeChip>>);
+
+    impl RadioBus for FakeBus {
+        fn transfer(&self, data: &mut [u8]) -> Result<(), BusError> {
+            let mut chip = self.0.borrow_mut();
+            chip.registers.resize(47, 0);
+            let len = data.len() - 1;
+            let header = data[0];
+            let reg_addr = (header & 0x3F) as usize;
+            let read = header & READ_SINGLE_BYTE != 0;
+            let burst = header & WRITE_BURST != 0;
+            if data.len() == 1 {
+                return Ok(()); // command strobe
+            }
+            match (read, reg_addr) {
+                (true, 0x30..=0x3D) if burst => {
+                    data[1] = match reg_addr {
+                        0x31 => 0x14, // VERSION
+                        0x35 => 0x0D, // MARCSTATE: RX
+                        0x3B => 1,    // RXBYTES
+                        _ => 0x00,
+                    }
+                }
+                (true, 0x3E) => data[1..].copy_from_slice(&chip.patable[..len]),
+                (true, 0x3F) => data[1..].fill(0),
+                (true, _) => data[1..].copy_from_slice(&chip.registers[reg_addr..reg_addr + len]),
+                (false, 0x3E) => chip.patable[..len].copy_from_slice(&data[1..]),
+                (false, 0x3F) => {}
+                (false, _) => chip.registers[reg_addr..reg_addr + len].copy_from_slice(&data[1..]),
+            }
+            data[0] = 0x0F;
+            Ok(())
+        }
+
+        fn gdo_is_high(&self, _gdo: Gdo) -> bool {
+            false
+        }
+
+        fn wait_gdo(&self, gdo: Gdo, _high: bool, _timeout: Duration) -> bool {
+            let mut chip = self.0.borrow_mut();
+            match gdo {
+                Gdo::Gdo0 => {
+                    let registers = chip.registers.clone();
+                    chip.gdo0_waits.push(registers);
+                    chip.gdo0.pop_front().unwrap_or(false)
+                }
+                Gdo::Gdo2 => chip.gdo2.pop_front().unwrap_or(false),
+            }
+        }
+    }
+
+    #[test]
+    fn sniff_after_lost_data_phase() {
+        let chip = Rc::new(RefCell::new(FakeChip::default()));
+        let cc1101 = CC1101::new(Box::new(FakeBus(chip.clone())), RegisterProfile::default());
+        let cc1101 = cc1101.unwrap();
+
+        // a first sync, then no second one
+        chip.borrow_mut().gdo0.extend([true, false]);
+        chip.borrow_mut().gdo2.push_back(true);
+        assert!(cc1101.sniff_frame(100).is_none());
+        assert_eq!(chip.borrow().registers[PKTCTRL0 as usize], 0x00);
+        assert_eq!(chip.borrow().registers[IOCFG2 as usize], 0x01);
+
+        assert!(cc1101.sniff_frame(100).is_none());
+        let chip = chip.borrow();
+        let sync_search = chip.gdo0_waits.last().unwrap();
+        for &(reg_addr, value) in &cc1101.profile().sync_search {
+            assert_eq!(sync_search[reg_addr as usize], value);
         }
+        assert_eq!(sync_search[IOCFG2 as usize], 0x01);
+        assert_eq!(chip.gdo0_waits.len(), 3);
+    }
+
+    #[test]
+    fn chip_status_from_byte() {
+        assert_eq!(
+            ChipStatus::from(0x2F),
+            ChipStatus {
+                ready: true,
+                state: ChipState::Tx,
+                fifo_bytes_available: 15,
+            }
+        );
+        assert_eq!(ChipStatus::from(0x70).state, ChipState::TxFifoUnderflow);
+        assert_eq!(ChipStatus::from(0x63).fifo_bytes_available, 3);
+        assert!(!ChipStatus::from(0x80).ready);
     }
 }
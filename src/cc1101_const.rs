@@ -58,6 +58,14 @@ pub const TEST2: u8 = 0x2C; // Various test settings
 pub const TEST1: u8 = 0x2D; // Various test settings
 pub const TEST0: u8 = 0x2E; // Various test settings
                             /*----------------------------[END config register]------------------------------*/
+pub const CONFIG_REGISTER_NAMES: [&str; 47] = [
+    "IOCFG2", "IOCFG1", "IOCFG0", "FIFOTHR", "SYNC1", "SYNC0", "PKTLEN", "PKTCTRL1", "PKTCTRL0",
+    "ADDR", "CHANNR", "FSCTRL1", "FSCTRL0", "FREQ2", "FREQ1", "FREQ0", "MDMCFG4", "MDMCFG3",
+    "MDMCFG2", "MDMCFG1", "MDMCFG0", "DEVIATN", "MCSM2", "MCSM1", "MCSM0", "FOCCFG", "BSCFG",
+    "AGCCTRL2", "AGCCTRL1", "AGCCTRL0", "WOREVT1", "WOREVT0", "WORCTRL", "FREND1", "FREND0",
+    "FSCAL3", "FSCAL2", "FSCAL1", "FSCAL0", "RCCTRL1", "RCCTRL0", "FSTEST", "PTEST", "AGCTEST",
+    "TEST2", "TEST1", "TEST0",
+];
 /*-------------------------[CC1100 - status register]----------------------------*/
 /* 0x3? is replace by 0xF? because for status register burst bit shall be set */
 pub const PARTNUM_ADDR: u8 = 0xF0; // Part number
@@ -1,6 +1,14 @@
+//! TOML configuration of the meter, the MQTT broker and Home Assistant discovery.
+
+use crate::fs_util::write_atomic;
+use crate::profile::{
+    is_driver_owned, pa_setting, register_address, DEFAULT_LBT_THRESHOLD_DBM, PA_433MHZ,
+};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use toml_edit::{DocumentMut, Item, Table, Value};
 use url::Url;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -8,6 +16,15 @@ pub struct Config {
     pub mqtt: MqttConfig,
     pub homeassistant: HomeAssistantConfig,
     pub meter: MeterConfig,
+    pub leak_detection: Option<LeakDetectionConfig>,
+    #[serde(default)]
+    pub radio: RadioConfig,
+    #[serde(default)]
+    pub radio_profile: RadioProfileConfig,
+    #[serde(default)]
+    pub scan: ScanConfig,
+    #[serde(default)]
+    pub poll: PollConfig,
     #[serde(default)]
     pub advanced: AdvancedConfig,
 }
@@ -40,6 +57,128 @@ pub struct MeterConfig {
     pub serial: u32,
     pub year: u8,
     pub location: Option<String>,
+    #[serde(default)]
+    pub index_offset: i64,
+    #[serde(default = "default_pulse_weight")]
+    pub pulse_weight: u32,
+    #[serde(default = "default_battery_warning_months")]
+    pub battery_warning_months: u32,
+}
+
+impl MeterConfig {
+    /// Convert the raw RADIAN register into the index shown on the physical dial.
+    pub fn calibrated_liters(&self, raw_liters: i32) -> i64 {
+        raw_liters as i64 * self.pulse_weight as i64 + self.index_offset
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LeakDetectionConfig {
+    pub continuous_flow_reads: Option<u32>,
+    pub night_flow: Option<NightFlowConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct NightFlowConfig {
+    #[serde(default = "default_night_start_hour")]
+    pub start_hour: u32,
+    #[serde(default = "default_night_end_hour")]
+    pub end_hour: u32,
+    pub threshold: f64, // in L/h
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RadioConfig {
+    #[serde(flatten)]
+    pub backend: RadioBackend,
+    #[serde(default = "default_tx_power_dbm")]
+    pub tx_power_dbm: i8,
+    #[serde(default = "default_wakeup_ms")]
+    pub wakeup_ms: u32, // 0x55 preamble waking the meter up before the request
+    #[serde(default = "default_lbt_threshold_dbm")]
+    pub lbt_threshold_dbm: i8, // channel taken as busy above this RSSI
+    #[serde(default = "default_duty_cycle_percent")]
+    pub duty_cycle_percent: f64, // of any hour spent transmitting
+}
+
+impl Default for RadioConfig {
+    fn default() -> Self {
+        Self {
+            backend: RadioBackend::default(),
+            tx_power_dbm: default_tx_power_dbm(),
+            wakeup_ms: default_wakeup_ms(),
+            lbt_threshold_dbm: default_lbt_threshold_dbm(),
+            duty_cycle_percent: default_duty_cycle_percent(),
+        }
+    }
+}
+
+/// How the CC1101 is wired to the host.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum RadioBackend {
+    /// Raspberry Pi: SPI0 CE0, GDO0 on BCM GPIO 17 and GDO2 on BCM GPIO 27
+    #[default]
+    Rppal,
+    /// Any Linux board through spidev and a GPIO character device
+    Linux {
+        spi_device: PathBuf,
+        gpio_chip: PathBuf,
+        gdo0_line: u32,
+        gdo2_line: u32,
+        #[serde(default = "default_spi_speed_hz")]
+        spi_speed_hz: u32,
+    },
+}
+
+/// Changes to the CC1101 register profile, registers by datasheet name.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RadioProfileConfig {
+    pub smartrf_file: Option<PathBuf>, // applied over the base registers
+    #[serde(default)]
+    pub base: BTreeMap<String, u8>,
+    #[serde(default)]
+    pub tx: BTreeMap<String, u8>,
+    #[serde(default)]
+    pub sync_search: BTreeMap<String, u8>,
+    #[serde(default)]
+    pub data: BTreeMap<String, u8>,
+}
+
+/// Sweep of the `scan` command, centered on the carrier of the radio profile.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ScanConfig {
+    #[serde(default = "default_span_khz")]
+    pub span_khz: u32, // whole width of the sweep
+    #[serde(default = "default_step_khz")]
+    pub step_khz: u32,
+    #[serde(default)]
+    pub publish: bool, // noise floor as a Home Assistant diagnostic sensor
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        Self {
+            span_khz: default_span_khz(),
+            step_khz: default_step_khz(),
+            publish: false,
+        }
+    }
+}
+
+/// Reads of the long-running `poll` command.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PollConfig {
+    #[serde(default = "default_interval_minutes")]
+    pub interval_minutes: u32,
+}
+
+impl Default for PollConfig {
+    fn default() -> Self {
+        Self {
+            interval_minutes: default_interval_minutes(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -50,6 +189,11 @@ pub struct AdvancedConfig {
     pub max_retries: u32,
     #[serde(default = "default_read_timeout_ms")]
     pub read_timeout_ms: u64,
+    #[serde(default = "default_state_file")]
+    pub state_file: PathBuf,
+    #[serde(default = "default_airtime_file")]
+    pub airtime_file: PathBuf,
+    pub capture_dir: Option<PathBuf>,
 }
 
 impl Default for AdvancedConfig {
@@ -58,10 +202,45 @@ impl Default for AdvancedConfig {
             timeout_ms: default_timeout_ms(),
             max_retries: default_max_retries(),
             read_timeout_ms: default_read_timeout_ms(),
+            state_file: default_state_file(),
+            airtime_file: default_airtime_file(),
+            capture_dir: None,
         }
     }
 }
 
+fn default_spi_speed_hz() -> u32 {
+    100_000
+}
+
+fn default_tx_power_dbm() -> i8 {
+    0
+}
+
+fn default_wakeup_ms() -> u32 {
+    2000
+}
+
+fn default_lbt_threshold_dbm() -> i8 {
+    DEFAULT_LBT_THRESHOLD_DBM
+}
+
+fn default_duty_cycle_percent() -> f64 {
+    10.0
+}
+
+fn default_span_khz() -> u32 {
+    400
+}
+
+fn default_step_khz() -> u32 {
+    10
+}
+
+fn default_interval_minutes() -> u32 {
+    60
+}
+
 fn default_qos() -> i32 {
     1
 }
@@ -82,6 +261,22 @@ fn default_model() -> String {
     "EverBlu Cyble Enhanced".to_string()
 }
 
+fn default_pulse_weight() -> u32 {
+    1
+}
+
+fn default_battery_warning_months() -> u32 {
+    12
+}
+
+fn default_night_start_hour() -> u32 {
+    1
+}
+
+fn default_night_end_hour() -> u32 {
+    5
+}
+
 fn default_timeout_ms() -> u64 {
     2000
 }
@@ -94,12 +289,21 @@ fn default_read_timeout_ms() -> u64 {
     5000
 }
 
+fn default_state_file() -> PathBuf {
+    PathBuf::from("/var/lib/hass-everblu-meter/history.json")
+}
+
+fn default_airtime_file() -> PathBuf {
+    PathBuf::from("/var/lib/hass-everblu-meter/airtime.json")
+}
+
 #[derive(Debug)]
 pub enum ConfigError {
     FileNotFound(String),
     ParseError(toml::de::Error),
     ValidationError(String),
     IoError(std::io::Error),
+    UpdateError(String),
 }
 
 impl std::fmt::Display for ConfigError {
@@ -117,6 +321,9 @@ impl std::fmt::Display for ConfigError {
             ConfigError::IoError(e) => {
                 write!(f, "Failed to read config file: {}", e)
             }
+            ConfigError::UpdateError(e) => {
+                write!(f, "Failed to update config file: {}", e)
+            }
         }
     }
 }
@@ -151,6 +358,18 @@ impl Config {
         Ok(config)
     }
 
+    /// Store register overrides, as (phase, register, value), in the `[radio_profile]` section of
+    /// the configuration file at `path`, keeping its comments and layout.
+    pub fn store_radio_profile<P: AsRef<Path>>(
+        path: P,
+        overrides: &[(&str, &str, u8)],
+    ) -> Result<(), ConfigError> {
+        let path = path.as_ref();
+        let content = set_radio_profile(&fs::read_to_string(path)?, overrides)?;
+        write_atomic(path, content)?;
+        Ok(())
+    }
+
     fn validate(&self) -> Result<(), ConfigError> {
         if self.meter.serial == 0 {
             return Err(ConfigError::ValidationError(
@@ -158,12 +377,124 @@ impl Config {
             ));
         }
 
+        if self.meter.pulse_weight == 0 {
+            return Err(ConfigError::ValidationError(
+                "Meter pulse_weight cannot be 0".to_string(),
+            ));
+        }
+
         if self.mqtt.qos < 0 || self.mqtt.qos > 2 {
             return Err(ConfigError::ValidationError(
                 "MQTT QoS must be 0, 1, or 2".to_string(),
             ));
         }
 
+        if let Some(leak_detection) = &self.leak_detection {
+            if leak_detection.continuous_flow_reads == Some(0) {
+                return Err(ConfigError::ValidationError(
+                    "Leak detection continuous_flow_reads must be at least 1".to_string(),
+                ));
+            }
+
+            if let Some(night_flow) = &leak_detection.night_flow {
+                if night_flow.start_hour > 23
+                    || night_flow.end_hour > 23
+                    || night_flow.start_hour == night_flow.end_hour
+                {
+                    return Err(ConfigError::ValidationError(
+                        "Leak detection night hours must be distinct values between 0 and 23"
+                            .to_string(),
+                    ));
+                }
+
+                if night_flow.threshold < 0.0 {
+                    return Err(ConfigError::ValidationError(
+                        "Leak detection night flow threshold cannot be negative".to_string(),
+                    ));
+                }
+            }
+        }
+
+        if let RadioBackend::Linux {
+            spi_speed_hz: 0, ..
+        } = self.radio.backend
+        {
+            return Err(ConfigError::ValidationError(
+                "Radio spi_speed_hz cannot be 0".to_string(),
+            ));
+        }
+
+        // 10 dBm conducted into a dipole is the 10 mW e.r.p. limit of the 433 MHz SRD band
+        // (ERC/REC 70-03)
+        if pa_setting(self.radio.tx_power_dbm).is_none() {
+            let levels: Vec<String> = PA_433MHZ.iter().map(|(dbm, _)| dbm.to_string()).collect();
+            return Err(ConfigError::ValidationError(format!(
+                "Radio tx_power_dbm must be one of {} dBm",
+                levels.join(", ")
+            )));
+        }
+
+        // the meter wakes up in about 2 s, a longer preamble only spends the duty cycle budget
+        if !(500..=5000).contains(&self.radio.wakeup_ms) {
+            return Err(ConfigError::ValidationError(
+                "Radio wakeup_ms must be between 500 and 5000".to_string(),
+            ));
+        }
+
+        if !(-110..=-40).contains(&self.radio.lbt_threshold_dbm) {
+            return Err(ConfigError::ValidationError(
+                "Radio lbt_threshold_dbm must be between -110 and -40".to_string(),
+            ));
+        }
+
+        // 10% is the limit of the 433.05-434.79 MHz SRD band at 10 mW (ERC/REC 70-03)
+        if !(self.radio.duty_cycle_percent > 0.0 && self.radio.duty_cycle_percent <= 10.0) {
+            return Err(ConfigError::ValidationError(
+                "Radio duty_cycle_percent must be above 0 and at most 10".to_string(),
+            ));
+        }
+
+        let profile = &self.radio_profile;
+        for name in [
+            &profile.base,
+            &profile.tx,
+            &profile.sync_search,
+            &profile.data,
+        ]
+        .into_iter()
+        .flat_map(|overrides| overrides.keys())
+        {
+            match register_address(name) {
+                None => {
+                    return Err(ConfigError::ValidationError(format!(
+                        "Radio profile register {} does not exist",
+                        name
+                    )))
+                }
+                Some(reg_addr) if is_driver_owned(reg_addr) => {
+                    return Err(ConfigError::ValidationError(format!(
+                        "Radio profile register {} is set by the driver",
+                        name
+                    )))
+                }
+                Some(_) => {}
+            }
+        }
+
+        if self.scan.step_khz == 0 || self.scan.span_khz / self.scan.step_khz > 200 {
+            return Err(ConfigError::ValidationError(
+                "Scan step_khz must be above 0 and give at most 200 steps over span_khz"
+                    .to_string(),
+            ));
+        }
+
+        // each read wakes the meter up and spends its battery
+        if self.poll.interval_minutes < 15 {
+            return Err(ConfigError::ValidationError(
+                "Poll interval_minutes must be at least 15".to_string(),
+            ));
+        }
+
         if self.homeassistant.node_id.is_empty() {
             return Err(ConfigError::ValidationError(
                 "Home Assistant node_id cannot be empty".to_string(),
@@ -180,6 +511,44 @@ impl Config {
     }
 }
 
+fn set_radio_profile(content: &str, overrides: &[(&str, &str, u8)]) -> Result<String, ConfigError> {
+    let mut document: DocumentMut = content
+        .parse()
+        .map_err(|e: toml_edit::TomlError| ConfigError::UpdateError(e.to_string()))?;
+    let radio_profile = document
+        .entry("radio_profile")
+        .or_insert_with(|| {
+            let mut table = Table::new();
+            table.set_implicit(true);
+            Item::Table(table)
+        })
+        .as_table_like_mut()
+        .ok_or_else(|| ConfigError::UpdateError("radio_profile is not a table".to_string()))?;
+
+    for &(phase, name, value) in overrides {
+        let registers = radio_profile
+            .entry(phase)
+            .or_insert_with(|| Item::Table(Table::new()))
+            .as_table_like_mut()
+            .ok_or_else(|| {
+                ConfigError::UpdateError(format!("radio_profile.{} is not a table", phase))
+            })?;
+        // register names are case insensitive, an override in another case would be duplicated
+        let keys: Vec<String> = registers
+            .iter()
+            .map(|(key, _)| key.to_string())
+            .filter(|key| key.eq_ignore_ascii_case(name))
+            .collect();
+        for key in keys {
+            registers.remove(&key);
+        }
+        let hex: Value = format!("0x{:02X}", value).parse().unwrap();
+        registers.insert(name, Item::Value(hex));
+    }
+
+    Ok(document.to_string())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -205,7 +574,15 @@ mod tests {
                 serial: 123456,
                 year: 14,
                 location: None,
+                index_offset: 0,
+                pulse_weight: 1,
+                battery_warning_months: 12,
             },
+            leak_detection: None,
+            radio: RadioConfig::default(),
+            radio_profile: RadioProfileConfig::default(),
+            scan: ScanConfig::default(),
+            poll: PollConfig::default(),
             advanced: AdvancedConfig::default(),
         };
 
@@ -217,5 +594,153 @@ mod tests {
         config.meter.serial = 123456;
         config.mqtt.qos = 3;
         assert!(config.validate().is_err());
+
+        config.mqtt.qos = 1;
+        config.meter.pulse_weight = 0;
+        assert!(config.validate().is_err());
+
+        config.meter.pulse_weight = 1;
+        config.leak_detection = Some(LeakDetectionConfig {
+            continuous_flow_reads: Some(0),
+            night_flow: None,
+        });
+        assert!(config.validate().is_err());
+
+        config.leak_detection = Some(LeakDetectionConfig {
+            continuous_flow_reads: None,
+            night_flow: Some(NightFlowConfig {
+                start_hour: 2,
+                end_hour: 2,
+                threshold: 5.0,
+            }),
+        });
+        assert!(config.validate().is_err());
+
+        config.leak_detection = None;
+        config
+            .radio_profile
+            .data
+            .insert("MDMCFG4".to_string(), 0xF7);
+        assert!(config.validate().is_ok());
+        config
+            .radio_profile
+            .data
+            .insert("MDMCFG9".to_string(), 0xF7);
+        assert!(config.validate().is_err());
+        config.radio_profile.data.clear();
+        config.radio_profile.base.insert("IOCFG0".to_string(), 0x06);
+        assert!(config.validate().is_err());
+
+        config.radio_profile.base.clear();
+        config.radio.tx_power_dbm = 12;
+        assert!(config.validate().is_err());
+        config.radio.tx_power_dbm = 7;
+        assert!(config.validate().is_ok());
+        config.radio.wakeup_ms = 6000;
+        assert!(config.validate().is_err());
+
+        config.radio.wakeup_ms = 2000;
+        config.radio.duty_cycle_percent = 1.0;
+        assert!(config.validate().is_ok());
+        config.radio.duty_cycle_percent = 25.0;
+        assert!(config.validate().is_err());
+        config.radio.duty_cycle_percent = 1.0;
+        config.radio.lbt_threshold_dbm = -20;
+        assert!(config.validate().is_err());
+
+        config.radio.lbt_threshold_dbm = -85;
+        config.scan.step_khz = 0;
+        assert!(config.validate().is_err());
+        config.scan.step_khz = 1;
+        assert!(config.validate().is_err());
+        config.scan.span_khz = 200;
+        assert!(config.validate().is_ok());
+
+        config.poll.interval_minutes = 5;
+        assert!(config.validate().is_err());
+        config.poll.interval_minutes = 15;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_calibrated_liters() {
+        let mut meter = MeterConfig {
+            serial: 123456,
+            year: 14,
+            location: None,
+            index_offset: 0,
+            pulse_weight: 1,
+            battery_warning_months: 12,
+        };
+        assert_eq!(meter.calibrated_liters(1107280), 1107280);
+
+        meter.pulse_weight = 10;
+        meter.index_offset = -2500;
+        assert_eq!(meter.calibrated_liters(1107280), 11070300);
+    }
+
+    #[test]
+    fn test_set_radio_profile() {
+        let content = "# Radio\n[radio]\ntx_power_dbm = 5 # close meter\n\n[radio_profile.data]\nmdmcfg4 = 0xF7\n";
+        let updated = set_radio_profile(
+            content,
+            &[("base", "FSCTRL0", 0x19), ("data", "MDMCFG4", 0xF8)],
+        )
+        .unwrap();
+        assert_eq!(
+            updated,
+            "# Radio\n[radio]\ntx_power_dbm = 5 # close meter\n\n[radio_profile.data]\nMDMCFG4 = 0xF8\n\n[radio_profile.base]\nFSCTRL0 = 0x19\n"
+        );
+
+        let profile: RadioProfileConfig = toml::from_str::<toml::Table>(&updated).unwrap()
+            ["radio_profile"]
+            .clone()
+            .try_into()
+            .unwrap();
+        assert_eq!(profile.base["FSCTRL0"], 0x19);
+        assert_eq!(profile.data["MDMCFG4"], 0xF8);
+    }
+
+    #[test]
+    fn test_radio_config() {
+        let radio: RadioConfig = toml::from_str(
+            r#"
+            backend = "linux"
+            spi_device = "/dev/spidev1.0"
+            gpio_chip = "/dev/gpiochip1"
+            gdo0_line = 91
+            gdo2_line = 92
+            "#,
+        )
+        .unwrap();
+        match radio.backend {
+            RadioBackend::Linux {
+                spi_device,
+                gdo0_line,
+                gdo2_line,
+                spi_speed_hz,
+                ..
+            } => {
+                assert_eq!(spi_device, PathBuf::from("/dev/spidev1.0"));
+                assert_eq!(gdo0_line, 91);
+                assert_eq!(gdo2_line, 92);
+                assert_eq!(spi_speed_hz, 100_000);
+            }
+            RadioBackend::Rppal => panic!("expected the linux backend"),
+        }
+        assert_eq!(radio.tx_power_dbm, 0);
+        assert_eq!(radio.wakeup_ms, 2000);
+
+        let radio: RadioConfig = toml::from_str(
+            r#"
+            backend = "rppal"
+            tx_power_dbm = 10
+            wakeup_ms = 3000
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(radio.backend, RadioBackend::Rppal));
+        assert_eq!(radio.tx_power_dbm, 10);
+        assert_eq!(radio.wakeup_ms, 3000);
     }
 }
@@ -0,0 +1,289 @@
+//! Software EverBlu meter and radio link, to run the whole exchange without hardware.
+
+use crate::exchange::{ExchangeError, LinkQuality, RadianLink, ReceivedFrame};
+use crate::profile::RegisterProfile;
+use crate::radian::{
+    check_crc, decode_serial_1_3, frame_crc, oversample_into, rx_window_len, Address,
+    CONTROL_REQUEST, CONTROL_RESPONSE, SYNCH_PATTERN,
+};
+use std::cell::{Cell, RefCell};
+
+// Real response captured from a meter, bytes not modelled by the emulator are taken from it
+const RESPONSE_TEMPLATE: &str = "7c110045200a501400450e0f94f800010f0050e51000400615011a03100d232e3032323033304242343100000612040141040000000000008080808080808080808080808080d8850f00ec9c0f0012b10f003ecc0f0026e70f007d021000c51f1000c54410006d6e1000fb85100053a11000eaba100035d31000f000";
+
+// Timings of scenario_releve, in microseconds
+const WAKE_UP_US: u32 = 2_000_000;
+const REQUEST_US: u32 = 130_000;
+const NOISE_US: u32 = 43_000;
+const PREAMBLE_US: u32 = 34_000 + 14_250 + 14_000;
+const SYNC_US: u32 = 34_000; // 0101...01 then 0000 is the sync word of the receiver
+const ACK_US: u32 = 83_500;
+const GAP_US: u32 = 50_000;
+const DATA_US: u32 = 582_000;
+
+#[derive(Debug, Clone)]
+pub struct MeterEmulator {
+    pub year: u8,
+    pub serial: u32,
+    pub liters: u32,
+    pub battery_left: u8,
+    pub time_start: u8,
+    pub time_end: u8,
+    pub reads_counter: u8,
+    pub history: [u32; 13],
+}
+
+impl MeterEmulator {
+    pub fn new(year: u8, serial: u32) -> Self {
+        Self {
+            year,
+            serial,
+            liters: 1107280,
+            battery_left: 46,
+            time_start: 6,
+            time_end: 18,
+            reads_counter: 65,
+            history: [0; 13],
+        }
+    }
+
+    /// Check an encoded request as produced by `make_radian_master_req`.
+    pub fn accepts(&self, txbuffer: &[u8]) -> bool {
+        let Some(encoded) = txbuffer.strip_prefix(&SYNCH_PATTERN[..]) else {
+            return false;
+        };
+        let request = decode_serial_1_3(encoded);
+
+        request.len() == 0x13
+            && request[0] == 0x13
+            && request[1] == CONTROL_REQUEST
+            && request[2..8] == self.address().to_bytes()
+            && check_crc(&request)
+    }
+
+    pub fn ack_frame(&self) -> Vec<u8> {
+        let mut frame = vec![0x12, CONTROL_RESPONSE];
+        frame.extend_from_slice(&Address::READER.to_bytes());
+        frame.extend_from_slice(&self.address().to_bytes());
+        frame.extend_from_slice(&[0x00, 0x0A, 0x00, 0x00]);
+        with_crc(frame)
+    }
+
+    pub fn data_frame(&self) -> Vec<u8> {
+        let mut frame = hex::decode(RESPONSE_TEMPLATE).unwrap();
+        frame[8..14].copy_from_slice(&self.address().to_bytes());
+        frame[18..22].copy_from_slice(&self.liters.to_le_bytes());
+        frame[31] = self.battery_left;
+        frame[44] = self.time_start;
+        frame[45] = self.time_end;
+        frame[48] = self.reads_counter;
+        for (i, liters) in self.history.iter().enumerate() {
+            frame[70 + i * 4..74 + i * 4].copy_from_slice(&liters.to_le_bytes());
+        }
+        with_crc(frame)
+    }
+
+    fn address(&self) -> Address {
+        Address::meter(self.year, self.serial)
+    }
+}
+
+fn with_crc(mut frame: Vec<u8>) -> Vec<u8> {
+    let len = frame.len();
+    let crc = frame_crc(&frame);
+    frame[len - 2..].copy_from_slice(&crc.to_le_bytes());
+    frame
+}
+
+/// `radian::oversample_into` into a buffer of the right length.
+pub fn oversample(frame: &[u8]) -> Vec<u8> {
+    let mut samples = vec![0; rx_window_len(frame.len())];
+    let len = oversample_into(frame, &mut samples);
+    samples.truncate(len);
+    samples
+}
+
+fn corrupted(mut frame: Vec<u8>, corrupt: bool) -> Vec<u8> {
+    if corrupt {
+        frame[15] ^= 0x10;
+    }
+    frame
+}
+
+struct Transmission {
+    start_us: u32,
+    end_us: u32,
+    frame: Vec<u8>,
+}
+
+/// Radio link to an emulated meter, with a virtual clock and optional sample flips.
+pub struct SimulatedLink {
+    pub meter: MeterEmulator,
+    pub link_quality: LinkQuality,
+    pub lose_data_frame: bool,
+    pub corrupt_ack: bool, // one bit of the index area flipped after the CRC was computed
+    pub corrupt_data_frame: bool, // same in the data frame
+    pub extra_latency_ms: u32, // slower firmware, before the ack and before the data frame
+    pub channel_rssi_dbm: i8, // heard before transmitting
+    noise_rate: f64,
+    rng: Cell<u64>,
+    clock_us: Cell<u32>,
+    on_air: RefCell<Vec<Transmission>>,
+    powered_down: Cell<bool>,
+}
+
+impl SimulatedLink {
+    pub fn new(meter: MeterEmulator) -> Self {
+        Self {
+            meter,
+            link_quality: LinkQuality {
+                rssi_dbm: -70,
+                lqi: 20,
+                freq_est: 0,
+            },
+            lose_data_frame: false,
+            corrupt_ack: false,
+            corrupt_data_frame: false,
+            extra_latency_ms: 0,
+            channel_rssi_dbm: -100,
+            noise_rate: 0.0,
+            rng: Cell::new(0x2545F4914F6CDD1D),
+            clock_us: Cell::new(0),
+            on_air: RefCell::new(Vec::new()),
+            powered_down: Cell::new(false),
+        }
+    }
+
+    /// Flip each received sample with the given probability, reproducibly for a given seed.
+    pub fn with_noise(mut self, rate: f64, seed: u64) -> Self {
+        self.noise_rate = rate;
+        self.rng = Cell::new(seed.max(1));
+        self
+    }
+
+    pub fn elapsed_ms(&self) -> u32 {
+        self.clock_us.get() / 1000
+    }
+
+    pub fn is_powered_down(&self) -> bool {
+        self.powered_down.get()
+    }
+
+    fn advance(&self, us: u32) {
+        self.clock_us.set(self.clock_us.get() + us);
+    }
+
+    // xorshift64, uniform in [0, 1)
+    fn random(&self) -> f64 {
+        let mut x = self.rng.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng.set(x);
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn add_noise(&self, samples: &mut [u8]) {
+        if self.noise_rate <= 0.0 {
+            return;
+        }
+        for byte in samples.iter_mut() {
+            for bit in 0..8 {
+                if self.random() < self.noise_rate {
+                    *byte ^= 1 << bit;
+                }
+            }
+        }
+    }
+}
+
+impl RadianLink for SimulatedLink {
+    fn transmit(&self, txbuffer: &[u8]) -> Result<(), ExchangeError> {
+        self.powered_down.set(false);
+        if self.channel_rssi_dbm > RegisterProfile::default().lbt_threshold_dbm {
+            return Err(ExchangeError::ChannelBusy(self.channel_rssi_dbm));
+        }
+        self.advance(WAKE_UP_US + REQUEST_US);
+        if !self.meter.accepts(txbuffer) {
+            return Ok(());
+        }
+
+        let latency_us = self.extra_latency_ms * 1000;
+        let ack_start = self.clock_us.get() + NOISE_US + latency_us;
+        let ack_end = ack_start + PREAMBLE_US + ACK_US;
+        let data_start = ack_end + GAP_US + latency_us;
+        let mut on_air = self.on_air.borrow_mut();
+        on_air.push(Transmission {
+            start_us: ack_start,
+            end_us: ack_end,
+            frame: corrupted(self.meter.ack_frame(), self.corrupt_ack),
+        });
+        if !self.lose_data_frame {
+            on_air.push(Transmission {
+                start_us: data_start,
+                end_us: data_start + PREAMBLE_US + DATA_US,
+                frame: corrupted(self.meter.data_frame(), self.corrupt_data_frame),
+            });
+        }
+        Ok(())
+    }
+
+    // A frame is caught when its sync word comes within the timeout
+    fn receive(&self, size_byte: i32, sync_tmo_ms: i32, rx_buffer: &mut [u8]) -> ReceivedFrame {
+        let now = self.clock_us.get();
+        let window_end = now + sync_tmo_ms as u32 * 1000;
+        let mut on_air = self.on_air.borrow_mut();
+        on_air.retain(|t| t.start_us + SYNC_US >= now);
+
+        let Some(index) = on_air.iter().position(|t| {
+            t.start_us + SYNC_US <= window_end && t.frame.len() == size_byte as usize
+        }) else {
+            self.clock_us.set(window_end);
+            return ReceivedFrame::default();
+        };
+        let transmission = on_air.remove(index);
+        let sync_us = transmission.start_us + SYNC_US;
+        self.clock_us.set(transmission.end_us);
+
+        // same length as receive_radian_frame reads from the FIFO
+        let byte_count = rx_window_len(size_byte as usize);
+        let mut samples = oversample(&transmission.frame);
+        samples.resize(byte_count, 0);
+        self.add_noise(&mut samples);
+        rx_buffer[..byte_count].copy_from_slice(&samples);
+
+        ReceivedFrame {
+            byte_count: byte_count as u16,
+            complete: true,
+            link_quality: self.link_quality,
+            sync_ms: (sync_us - now) / 1000,
+            frame_ms: (transmission.end_us - sync_us) / 1000,
+        }
+    }
+
+    fn power_down(&self) {
+        self.powered_down.set(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::radian::{decode_4bitpbit_serial, make_radian_master_req};
+
+    #[test]
+    fn emulated_frames_decode() {
+        let meter = MeterEmulator::new(16, 1087372);
+        assert!(meter.accepts(&make_radian_master_req(16, 1087372)));
+        assert!(!meter.accepts(&make_radian_master_req(16, 1087373)));
+
+        for frame in [meter.ack_frame(), meter.data_frame()] {
+            assert!(check_crc(&frame));
+            let samples = oversample(&frame);
+            assert_eq!(
+                decode_4bitpbit_serial(&samples, samples.len() as u16),
+                frame
+            );
+        }
+    }
+}
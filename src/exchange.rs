@@ -0,0 +1,399 @@
+//! Request, acknowledgement and data frame exchange with a meter over a [`RadianLink`].
+
+use crate::capture::{Capture, FrameKind};
+use crate::radian::{
+    check_crc, decode_oversampled, make_radian_master_req, parse_meter_report, Address, MeterData,
+};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+/// Signal of a received frame, read from the CC1101 status registers after the sync word.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct LinkQuality {
+    /// Received signal strength in dBm
+    pub rssi_dbm: i8,
+    /// Link quality indicator, lower is better
+    pub lqi: u8,
+    /// Frequency offset estimate, two's complement in steps of 26 MHz / 2^14
+    pub freq_est: u8,
+}
+
+/// Outcome of a receive, the oversampled bytes themselves go to the caller's buffer.
+#[derive(Debug, Default)]
+pub struct ReceivedFrame {
+    /// Bytes pulled from the FIFO, even when the frame is incomplete
+    pub byte_count: u16,
+    /// Whether the whole receive window was read before the deadline
+    pub complete: bool,
+    pub link_quality: LinkQuality,
+    /// From the start of the receive to the sync word
+    pub sync_ms: u32,
+    /// From the sync word to the last byte
+    pub frame_ms: u32,
+}
+
+const ACK_SIZE: usize = 0x12;
+const DATA_SIZE: usize = 0x7C;
+
+// The meter answers 43 ms after the request and sends its data frame 50 ms after the ack, each
+// behind a 34 ms preamble. The margin is for slower firmware, the frames end on their own.
+const ACK_SYNC_TMO_MS: i32 = 300;
+const DATA_SYNC_TMO_MS: i32 = 300;
+
+#[derive(Debug, PartialEq)]
+pub enum ExchangeError {
+    NoAck,           // the meter never heard the request
+    NoData,          // the meter answered but the data frame was lost
+    ChannelBusy(i8), // RSSI in dBm, nothing was transmitted
+    BadAck(String),  // the ack was invalid and no data frame followed
+    BadData(String), // truncated, corrupted or from another meter
+    ParseError(String),
+}
+
+impl std::fmt::Display for ExchangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExchangeError::NoAck => write!(
+                f,
+                "No acknowledgement from the meter, it did not hear the request"
+            ),
+            ExchangeError::NoData => write!(
+                f,
+                "Meter acknowledged the request but its data frame was lost"
+            ),
+            ExchangeError::ChannelBusy(rssi_dbm) => write!(
+                f,
+                "Channel busy at {} dBm, the request was not transmitted",
+                rssi_dbm
+            ),
+            ExchangeError::BadAck(e) => write!(
+                f,
+                "Invalid acknowledgement from the meter ({}) and no data frame followed",
+                e
+            ),
+            ExchangeError::BadData(e) => write!(f, "Invalid data frame from the meter: {}", e),
+            ExchangeError::ParseError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ExchangeError {}
+
+/// Check a decoded acknowledgement frame comes intact from the meter that was queried.
+pub fn validate_ack(frame: &[u8], year: u8, serial: u32) -> Result<(), String> {
+    validate_frame(frame, ACK_SIZE, year, serial).map(|_| ())
+}
+
+/// Check a decoded data frame comes intact from the meter that was queried, and return it
+/// without the bytes decoded past its end.
+pub fn validate_data(frame: &[u8], year: u8, serial: u32) -> Result<&[u8], String> {
+    validate_frame(frame, DATA_SIZE, year, serial)
+}
+
+fn validate_frame(frame: &[u8], size: usize, year: u8, serial: u32) -> Result<&[u8], String> {
+    if frame.len() < size {
+        return Err(format!("{} bytes received, {} expected", frame.len(), size));
+    }
+    if frame[0] as usize != size {
+        return Err(format!("length byte 0x{:02X}", frame[0]));
+    }
+    let frame = &frame[..size];
+    if !check_crc(frame) {
+        return Err("bad CRC".to_string());
+    }
+
+    let source = Address::from_bytes(&frame[8..14]);
+    if source.year != year || source.serial != serial {
+        return Err(format!(
+            "sent by meter year={} serial={}",
+            source.year, source.serial
+        ));
+    }
+    Ok(frame)
+}
+
+/// Radio carrying a RADIAN exchange: the CC1101, or a simulated link in tests.
+pub trait RadianLink {
+    /// Send the wake-up preamble followed by an encoded request, once the channel is clear.
+    fn transmit(&self, txbuffer: &[u8]) -> Result<(), ExchangeError>;
+
+    /// Wait up to `sync_tmo_ms` for a frame to start, then receive its `size_byte` bytes into
+    /// `rx_buffer`, 4 samples per bit.
+    fn receive(&self, size_byte: i32, sync_tmo_ms: i32, rx_buffer: &mut [u8]) -> ReceivedFrame;
+
+    /// Exchange over: save power until the next transmit or receive.
+    fn power_down(&self) {}
+
+    /// Register configuration recorded in captures.
+    fn registers(&self) -> Vec<u8> {
+        Vec::new()
+    }
+}
+
+/*
+scenario_releve
+2s de WUP
+130ms : trame interrogation de l'outils de reléve   ______------|...............-----
+43ms de bruit
+34ms 0101...01
+14.25ms 000...000
+14ms 1111...11111
+83.5ms de data acquitement
+50ms de 111111
+34ms 0101...01
+14.25ms 000...000
+14ms 1111...11111
+582ms de data avec l'index
+
+l'outils de reléve doit normalement acquité
+*/
+pub fn get_meter_data(
+    link: &impl RadianLink,
+    year: u8,
+    serial: u32,
+    capture: Option<&mut Capture>,
+) -> Result<MeterData, ExchangeError> {
+    let result = exchange(link, year, serial, capture);
+    link.power_down();
+    result
+}
+
+fn exchange(
+    link: &impl RadianLink,
+    year: u8,
+    serial: u32,
+    mut capture: Option<&mut Capture>,
+) -> Result<MeterData, ExchangeError> {
+    let mut rx_buffer: [u8; 1000] = [0; 1000];
+
+    // let mut txbuffer: [u8; 100] = [0; 100];
+    let txbuffer = make_radian_master_req(year, serial);
+
+    if let Some(capture) = capture.as_mut() {
+        capture.registers = link.registers();
+    }
+
+    link.transmit(&txbuffer)?;
+
+    // 43ms de bruit
+    // 34ms 0101...01  14.25ms 000...000  14ms 1111...11111  83.5ms de data acquitement
+    let ack = link.receive(ACK_SIZE as i32, ACK_SYNC_TMO_MS, &mut rx_buffer);
+    if let Some(capture) = capture.as_mut() {
+        capture.push_frame(FrameKind::Ack, ack.link_quality, &rx_buffer, ack.byte_count);
+    }
+    if !ack.complete {
+        debug!("TMO on REC");
+        return Err(ExchangeError::NoAck);
+    }
+    debug!(
+        "ack sync {} ms after the request, received in {} ms",
+        ack.sync_ms, ack.frame_ms
+    );
+    // the data frame is still worth listening for when the ack was damaged
+    let ack_frame = decode_oversampled(&rx_buffer[..ack.byte_count as usize]);
+    let ack_result = validate_ack(&ack_frame.bytes, year, serial);
+    match &ack_result {
+        Ok(()) => info!("Meter acknowledged the request"),
+        Err(e) => warn!("Invalid acknowledgement from the meter: {}", e),
+    }
+
+    // 50ms de 111111
+    // 34ms 0101...01  14.25ms 000...000  14ms 1111...11111  582ms de data avec l'index
+    let data = link.receive(DATA_SIZE as i32, DATA_SYNC_TMO_MS, &mut rx_buffer);
+    if let Some(capture) = capture.as_mut() {
+        capture.push_frame(
+            FrameKind::Data,
+            data.link_quality,
+            &rx_buffer,
+            data.byte_count,
+        );
+    }
+    if data.complete {
+        debug!("{:02X?}", rx_buffer);
+        info!(
+            "Timings: ack sync after {} ms, ack {} ms, data sync {} ms after the ack, data {} ms",
+            ack.sync_ms, ack.frame_ms, data.sync_ms, data.frame_ms
+        );
+
+        let meter_data = decode_oversampled(&rx_buffer[..data.byte_count as usize]);
+        debug!(
+            "decoded {} bytes, {} glitches corrected, {} framing errors, min confidence {:.2}",
+            meter_data.bytes.len(),
+            meter_data.corrected_glitches,
+            meter_data.framing_errors,
+            meter_data.min_confidence()
+        );
+        let frame =
+            validate_data(&meter_data.bytes, year, serial).map_err(|e| match ack_result {
+                Ok(()) => ExchangeError::BadData(e),
+                Err(ack_e) => {
+                    ExchangeError::BadData(format!("{}, after an invalid ack: {}", e, ack_e))
+                }
+            })?;
+        parse_meter_report(frame).map_err(|e| ExchangeError::ParseError(e.to_string()))
+    } else {
+        debug!("TMO on REC");
+        Err(match ack_result {
+            Ok(()) => ExchangeError::NoData,
+            Err(e) => ExchangeError::BadAck(e),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{oversample, MeterEmulator, SimulatedLink};
+    use crate::radian::check_crc;
+
+    #[test]
+    fn read_emulated_meter() {
+        let mut meter = MeterEmulator::new(16, 1087372);
+        meter.liters = 123456;
+        meter.battery_left = 120;
+        meter.time_start = 8;
+        meter.time_end = 20;
+        let link = SimulatedLink::new(meter);
+
+        let mut capture = Capture::new(16, 1087372);
+        let data = get_meter_data(&link, 16, 1087372, Some(&mut capture)).unwrap();
+        assert_eq!(data.liters, 123456);
+        assert_eq!(data.battery_left, 120);
+        assert_eq!(data.time_start, 8);
+        assert_eq!(data.time_end, 20);
+        assert_eq!(data.reads_counter, 65);
+        assert_eq!(capture.frames.len(), 2);
+        assert!(check_crc(&capture.frames[1].decode()));
+        assert!(link.elapsed_ms() < 3500);
+        assert!(link.is_powered_down());
+    }
+
+    #[test]
+    fn wrong_serial_times_out() {
+        let link = SimulatedLink::new(MeterEmulator::new(16, 1087372));
+
+        let result = get_meter_data(&link, 16, 1087373, None);
+        assert_eq!(result.unwrap_err(), ExchangeError::NoAck);
+        assert_eq!(link.elapsed_ms(), 2130 + ACK_SYNC_TMO_MS as u32);
+        assert!(link.is_powered_down());
+    }
+
+    #[test]
+    fn lost_data_frame() {
+        let mut link = SimulatedLink::new(MeterEmulator::new(16, 1087372));
+        link.lose_data_frame = true;
+
+        let result = get_meter_data(&link, 16, 1087372, None);
+        assert_eq!(result.unwrap_err(), ExchangeError::NoData);
+    }
+
+    #[test]
+    fn validate_ack_works() {
+        let ack = MeterEmulator::new(16, 1087372).ack_frame();
+        assert_eq!(validate_ack(&ack, 16, 1087372), Ok(()));
+        assert_eq!(
+            validate_ack(&ack, 16, 1087373),
+            Err("sent by meter year=16 serial=1087372".to_string())
+        );
+        assert!(validate_ack(&ack[..10], 16, 1087372).is_err());
+        assert_eq!(
+            validate_ack(&ack[..16], 16, 1087372),
+            Err("16 bytes received, 18 expected".to_string())
+        );
+
+        let mut damaged = ack.clone();
+        damaged[15] ^= 0x01;
+        assert_eq!(
+            validate_ack(&damaged, 16, 1087372),
+            Err("bad CRC".to_string())
+        );
+    }
+
+    #[test]
+    fn validate_ack_needs_its_crc() {
+        // a window ending in the middle of the first CRC byte
+        let ack = MeterEmulator::new(16, 1087372).ack_frame();
+        let samples = oversample(&ack);
+        let decoded = decode_oversampled(&samples[..(16 * 44 + 20) / 8]);
+        assert!(decoded.truncated);
+        assert_eq!(
+            validate_ack(&decoded.bytes, 16, 1087372),
+            Err("17 bytes received, 18 expected".to_string())
+        );
+    }
+
+    #[test]
+    fn validate_data_works() {
+        let data = MeterEmulator::new(16, 1087372).data_frame();
+        let mut decoded = data.clone();
+        decoded.push(0x00); // decoded past the end of the frame
+        assert_eq!(validate_data(&decoded, 16, 1087372), Ok(&data[..]));
+        assert_eq!(
+            validate_data(&data[..123], 16, 1087372),
+            Err("123 bytes received, 124 expected".to_string())
+        );
+        assert_eq!(
+            validate_data(&data, 16, 1087373),
+            Err("sent by meter year=16 serial=1087372".to_string())
+        );
+    }
+
+    #[test]
+    fn corrupted_data_frame() {
+        let mut link = SimulatedLink::new(MeterEmulator::new(16, 1087372));
+        link.corrupt_data_frame = true;
+        let result = get_meter_data(&link, 16, 1087372, None);
+        assert_eq!(
+            result.unwrap_err(),
+            ExchangeError::BadData("bad CRC".to_string())
+        );
+
+        link.corrupt_ack = true;
+        let result = get_meter_data(&link, 16, 1087372, None);
+        assert_eq!(
+            result.unwrap_err(),
+            ExchangeError::BadData("bad CRC, after an invalid ack: bad CRC".to_string())
+        );
+    }
+
+    #[test]
+    fn corrupted_ack_then_lost_data() {
+        let mut link = SimulatedLink::new(MeterEmulator::new(16, 1087372));
+        link.corrupt_ack = true;
+        link.lose_data_frame = true;
+        let result = get_meter_data(&link, 16, 1087372, None);
+        assert_eq!(
+            result.unwrap_err(),
+            ExchangeError::BadAck("bad CRC".to_string())
+        );
+    }
+
+    #[test]
+    fn busy_channel_defers_request() {
+        let mut link = SimulatedLink::new(MeterEmulator::new(16, 1087372));
+        link.channel_rssi_dbm = -60;
+
+        let result = get_meter_data(&link, 16, 1087372, None);
+        assert_eq!(result.unwrap_err(), ExchangeError::ChannelBusy(-60));
+        assert_eq!(link.elapsed_ms(), 0);
+    }
+
+    #[test]
+    fn read_slow_meter() {
+        let mut link = SimulatedLink::new(MeterEmulator::new(16, 1087372));
+        link.extra_latency_ms = 150;
+
+        let data = get_meter_data(&link, 16, 1087372, None).unwrap();
+        assert_eq!(data.liters, 1107280);
+    }
+
+    #[test]
+    fn read_with_isolated_glitches() {
+        let mut meter = MeterEmulator::new(16, 1087372);
+        meter.liters = 987654;
+        let link = SimulatedLink::new(meter).with_noise(0.005, 7);
+
+        let data = get_meter_data(&link, 16, 1087372, None).unwrap();
+        assert_eq!(data.liters, 987654);
+    }
+}
@@ -0,0 +1,64 @@
+//! File writes shared by the configuration, history and airtime files.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Replace the content of `path`, keeping the permissions of the file it replaces.
+/// Written then renamed, so that an interrupted run never leaves a truncated file.
+pub fn write_atomic<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> io::Result<()> {
+    let path = path.as_ref();
+    let tmp_path = path.with_extension("tmp");
+
+    let mut file = File::create(&tmp_path)?;
+    // before any content, a config holding a password must never be readable by others
+    if let Ok(metadata) = fs::metadata(path) {
+        file.set_permissions(metadata.permissions())?;
+    }
+    file.write_all(contents.as_ref())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Check `write_atomic` can replace `path`: the file opens for writing and its directory takes
+/// the temporary file. Nothing is changed.
+pub fn check_writable<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let path = path.as_ref();
+    OpenOptions::new().append(true).open(path)?;
+
+    let tmp_path = path.with_extension("tmp");
+    File::create(&tmp_path)?;
+    fs::remove_file(&tmp_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn write_atomic_keeps_permissions() {
+        let path = std::env::temp_dir().join(format!("write_atomic_{}.toml", std::process::id()));
+        fs::write(&path, "password = \"secret\"\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        write_atomic(&path, "password = \"changed\"\n").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(content, "password = \"changed\"\n");
+    }
+
+    #[test]
+    fn check_writable_works() {
+        let path = std::env::temp_dir().join(format!("check_writable_{}.toml", std::process::id()));
+        assert!(check_writable(&path).is_err());
+
+        fs::write(&path, "").unwrap();
+        assert!(check_writable(&path).is_ok());
+        assert!(!path.with_extension("tmp").exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
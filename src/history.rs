@@ -0,0 +1,265 @@
+//! Readings kept between runs, and the consumption computed from them.
+
+use crate::fs_util::write_atomic;
+use chrono::{DateTime, Datelike, NaiveDate, NaiveTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+// Always keep this many readings, even when they are older than the current month
+const MIN_READINGS: usize = 64;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reading {
+    pub timestamp: DateTime<Utc>,
+    pub liters: i64,
+}
+
+/// Meter the readings come from, and the conversion of its index to liters.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Calibration {
+    pub year: u8,
+    pub serial: u32,
+    pub index_offset: i64,
+    pub pulse_weight: u32,
+}
+
+/// Readings persisted between runs, oldest first.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct History {
+    #[serde(default)]
+    calibration: Option<Calibration>, // unknown in files written before it was recorded
+    readings: Vec<Reading>,
+}
+
+// Unknown values are left out rather than published as null, which sensors with a unit reject
+#[derive(Debug, Default, Serialize)]
+pub struct Consumption {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub consumption_since_last_read: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flow_rate: Option<f64>, // in L/h, averaged since the last read
+    pub consumption_today: i64,
+    pub consumption_this_month: i64,
+}
+
+#[derive(Debug)]
+pub enum HistoryError {
+    IoError(std::io::Error),
+    ParseError(serde_json::Error),
+}
+
+impl std::fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HistoryError::IoError(e) => write!(f, "Failed to access history file: {}", e),
+            HistoryError::ParseError(e) => write!(f, "Failed to parse history file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HistoryError {}
+
+impl From<std::io::Error> for HistoryError {
+    fn from(err: std::io::Error) -> Self {
+        HistoryError::IoError(err)
+    }
+}
+
+impl From<serde_json::Error> for HistoryError {
+    fn from(err: serde_json::Error) -> Self {
+        HistoryError::ParseError(err)
+    }
+}
+
+impl History {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, HistoryError> {
+        let path = path.as_ref();
+
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let content = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), HistoryError> {
+        write_atomic(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Forget the readings taken from another meter or with another calibration: against the
+    /// new index they would give a bogus consumption. Returns whether the history was reset.
+    pub fn set_calibration(&mut self, calibration: Calibration) -> bool {
+        let changed = self
+            .calibration
+            .as_ref()
+            .is_some_and(|previous| *previous != calibration);
+        if changed {
+            self.readings.clear();
+        }
+        self.calibration = Some(calibration);
+        changed
+    }
+
+    pub fn readings(&self) -> &[Reading] {
+        &self.readings
+    }
+
+    /// Compute the consumption of a new index against the stored readings.
+    /// Day and month boundaries are taken in the time zone of `now`.
+    pub fn consumption<Tz: TimeZone>(&self, liters: i64, now: &DateTime<Tz>) -> Consumption {
+        let Some(last) = self.readings.last() else {
+            return Consumption::default();
+        };
+
+        let since_last_read = liters - last.liters;
+        let hours = (now.to_utc() - last.timestamp).num_seconds() as f64 / 3600.0;
+
+        Consumption {
+            consumption_since_last_read: Some(since_last_read),
+            flow_rate: (hours > 0.0).then(|| since_last_read as f64 / hours),
+            consumption_today: liters - self.baseline(day_start(now)),
+            consumption_this_month: liters - self.baseline(month_start(now)),
+        }
+    }
+
+    /// Append a reading and drop the ones no longer needed to compute monthly totals.
+    pub fn record<Tz: TimeZone>(&mut self, reading: Reading, now: &DateTime<Tz>) {
+        self.readings.push(reading);
+
+        let month_start = month_start(now);
+        let baseline = self
+            .readings
+            .iter()
+            .rposition(|r| r.timestamp < month_start)
+            .unwrap_or(0);
+        let excess = self.readings.len().saturating_sub(MIN_READINGS);
+        self.readings.drain(..baseline.min(excess));
+    }
+
+    // Index at the start of a period: the last reading before it, or the first one within it
+    fn baseline(&self, period_start: DateTime<Utc>) -> i64 {
+        self.readings
+            .iter()
+            .rev()
+            .find(|r| r.timestamp < period_start)
+            .or_else(|| self.readings.first())
+            .map(|r| r.liters)
+            .unwrap_or_default()
+    }
+}
+
+fn day_start<Tz: TimeZone>(now: &DateTime<Tz>) -> DateTime<Utc> {
+    local_midnight(now, now.date_naive())
+}
+
+fn month_start<Tz: TimeZone>(now: &DateTime<Tz>) -> DateTime<Utc> {
+    local_midnight(now, now.date_naive().with_day(1).unwrap())
+}
+
+fn local_midnight<Tz: TimeZone>(now: &DateTime<Tz>, date: NaiveDate) -> DateTime<Utc> {
+    date.and_time(NaiveTime::MIN)
+        .and_local_timezone(now.timezone())
+        .earliest()
+        .map(|d| d.to_utc())
+        .unwrap_or_else(|| now.to_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+    }
+
+    fn history(readings: &[(&str, i64)]) -> History {
+        History {
+            calibration: None,
+            readings: readings
+                .iter()
+                .map(|&(timestamp, liters)| Reading {
+                    timestamp: at(timestamp),
+                    liters,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn consumption_without_history() {
+        let consumption = History::default().consumption(1000, &at("2026-03-10T07:00:00Z"));
+        assert_eq!(consumption.consumption_since_last_read, None);
+        assert_eq!(consumption.flow_rate, None);
+        assert_eq!(consumption.consumption_today, 0);
+        assert_eq!(consumption.consumption_this_month, 0);
+        assert_eq!(
+            serde_json::to_string(&consumption).unwrap(),
+            r#"{"consumption_today":0,"consumption_this_month":0}"#
+        );
+    }
+
+    #[test]
+    fn consumption_against_previous_readings() {
+        let history = history(&[
+            ("2026-02-27T07:00:00Z", 800),
+            ("2026-03-09T07:00:00Z", 1000),
+            ("2026-03-10T01:00:00Z", 1100),
+        ]);
+
+        let consumption = history.consumption(1250, &at("2026-03-10T07:00:00Z"));
+        assert_eq!(consumption.consumption_since_last_read, Some(150));
+        assert_eq!(consumption.flow_rate, Some(25.0));
+        assert_eq!(consumption.consumption_today, 250);
+        assert_eq!(consumption.consumption_this_month, 450);
+    }
+
+    #[test]
+    fn record_keeps_month_baseline() {
+        let mut history = history(&[("2025-12-01T07:00:00Z", 0)]);
+        for day in 0..100 {
+            let now = at("2026-01-01T07:00:00Z") + chrono::Duration::days(day);
+            history.record(
+                Reading {
+                    timestamp: now,
+                    liters: day * 10,
+                },
+                &now,
+            );
+        }
+
+        // Reads run up to 2026-04-10, the April baseline is the 2026-03-31 read
+        assert_eq!(history.readings.len(), MIN_READINGS);
+        let now = at("2026-04-10T08:00:00Z");
+        assert_eq!(
+            history.consumption(1000, &now).consumption_this_month,
+            1000 - 890
+        );
+    }
+
+    #[test]
+    fn set_calibration_resets_on_change() {
+        let calibration = Calibration {
+            year: 16,
+            serial: 1087372,
+            index_offset: 0,
+            pulse_weight: 1,
+        };
+        // files written before the calibration was recorded keep their readings
+        let mut history: History = serde_json::from_str(
+            r#"{"readings":[{"timestamp":"2026-03-10T07:00:00Z","liters":1000}]}"#,
+        )
+        .unwrap();
+        assert!(!history.set_calibration(calibration.clone()));
+        assert!(!history.set_calibration(calibration.clone()));
+        assert_eq!(history.readings.len(), 1);
+
+        assert!(history.set_calibration(Calibration {
+            pulse_weight: 10,
+            ..calibration
+        }));
+        assert!(history.readings.is_empty());
+    }
+}
@@ -0,0 +1,287 @@
+//! Field by field annotation of RADIAN frames.
+
+use crate::radian::{
+    check_crc, decode_oversampled, decode_serial_1_3, frame_crc, CONTROL_REQUEST, CONTROL_RESPONSE,
+    SYNCH_PATTERN,
+};
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub enum InputKind {
+    Oversampled, // raw receive buffer, 4 samples per bit with start/stop bits
+    Encoded,     // synch pattern followed by a start/stop bit encoded request
+    Decoded,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CrcStatus {
+    Valid,
+    Invalid { computed: u16, stored: u16 },
+    Truncated { declared: usize, actual: usize },
+}
+
+#[derive(Debug)]
+pub struct Field {
+    pub offset: usize,
+    pub bytes: Vec<u8>,
+    pub name: &'static str,
+    pub value: String,
+}
+
+/// Field by field description of a RADIAN frame.
+#[derive(Debug)]
+pub struct Inspection {
+    pub input: InputKind,
+    pub frame: Vec<u8>,
+    pub crc: CrcStatus,
+    pub fields: Vec<Field>,
+}
+
+/// Annotate a frame given as a raw receive buffer, an encoded request or an already decoded frame.
+pub fn inspect(input: &[u8]) -> Inspection {
+    let (input_kind, frame) = if looks_decoded(input) {
+        (InputKind::Decoded, input.to_vec())
+    } else if input.starts_with(&SYNCH_PATTERN) {
+        (
+            InputKind::Encoded,
+            decode_serial_1_3(&input[SYNCH_PATTERN.len()..]),
+        )
+    } else {
+        // the whole buffer, whatever its length: no u16 byte count to overflow
+        (InputKind::Oversampled, decode_oversampled(input).bytes)
+    };
+
+    Inspection {
+        input: input_kind,
+        crc: crc_status(&frame),
+        fields: describe(&frame),
+        frame,
+    }
+}
+
+// Decoded frames start with their own length; the decoder may drop the last byte
+fn looks_decoded(input: &[u8]) -> bool {
+    input.len() >= 2
+        && matches!(input[1], CONTROL_REQUEST | CONTROL_RESPONSE)
+        && (input[0] as usize == input.len() || input[0] as usize == input.len() + 1)
+}
+
+fn crc_status(frame: &[u8]) -> CrcStatus {
+    let declared = frame.first().copied().unwrap_or_default() as usize;
+    if frame.len() < declared || frame.len() < 3 {
+        return CrcStatus::Truncated {
+            declared,
+            actual: frame.len(),
+        };
+    }
+
+    let frame = &frame[..declared.max(3)];
+    if check_crc(frame) {
+        CrcStatus::Valid
+    } else {
+        CrcStatus::Invalid {
+            computed: frame_crc(frame),
+            stored: u16::from_le_bytes([frame[frame.len() - 2], frame[frame.len() - 1]]),
+        }
+    }
+}
+
+fn describe(frame: &[u8]) -> Vec<Field> {
+    let mut fields = FieldList::new(frame);
+
+    fields.push(0, 1, "Length", |b| format!("{} bytes", b[0]));
+    fields.push(1, 1, "Control", |b| match b[0] {
+        CONTROL_REQUEST => "request".to_string(),
+        CONTROL_RESPONSE => "response".to_string(),
+        other => format!("unknown (0x{:02X})", other),
+    });
+    fields.push(2, 6, "Destination", address);
+    fields.push(8, 6, "Source", address);
+
+    let declared = frame.first().copied().unwrap_or_default() as usize;
+    let crc_offset = declared.saturating_sub(2).max(14);
+    match frame.get(1) {
+        // Layout of RadianRequest
+        Some(&CONTROL_REQUEST) => {
+            fields.push(14, crc_offset - 14, "Command", |b| hex::encode(b));
+        }
+        // Layout of parse_meter_report
+        Some(&CONTROL_RESPONSE) if declared > 0x12 => {
+            fields.push(14, 4, "Unknown", unknown);
+            fields.push(18, 4, "Index", |b| format!("{} L", le_u32(b)));
+            fields.push(22, 9, "Unknown", unknown);
+            fields.push(31, 1, "Battery left", |b| format!("{} months", b[0]));
+            fields.push(32, 12, "Unknown", unknown);
+            fields.push(44, 1, "Wake hour", |b| format!("{:02}:00", b[0]));
+            fields.push(45, 1, "Sleep hour", |b| format!("{:02}:00", b[0]));
+            fields.push(46, 2, "Unknown", unknown);
+            fields.push(48, 1, "Reads counter", |b| b[0].to_string());
+            fields.push(49, 21, "Unknown", unknown);
+            fields.push(70, crc_offset.saturating_sub(70), "Index history", |b| {
+                b.chunks_exact(4)
+                    .map(|c| format!("{} L", le_u32(c)))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            });
+        }
+        _ => {
+            fields.push(14, crc_offset - 14, "Payload", |b| hex::encode(b));
+        }
+    }
+    fields.push(crc_offset, 2, "CRC", |b| {
+        format!("0x{:04X}", u16::from_le_bytes([b[0], b[1]]))
+    });
+
+    fields.fields
+}
+
+struct FieldList<'a> {
+    frame: &'a [u8],
+    fields: Vec<Field>,
+}
+
+impl<'a> FieldList<'a> {
+    fn new(frame: &'a [u8]) -> Self {
+        Self {
+            frame,
+            fields: Vec::new(),
+        }
+    }
+
+    // Fields extending past the end of a truncated frame are left out
+    fn push(
+        &mut self,
+        offset: usize,
+        len: usize,
+        name: &'static str,
+        interpret: impl Fn(&[u8]) -> String,
+    ) {
+        if let Some(bytes) = self.frame.get(offset..offset + len) {
+            if !bytes.is_empty() {
+                self.fields.push(Field {
+                    offset,
+                    bytes: bytes.to_vec(),
+                    name,
+                    value: interpret(bytes),
+                });
+            }
+        }
+    }
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+// 2 byte prefix, then year and 3 byte serial for a meter
+fn address(bytes: &[u8]) -> String {
+    format!(
+        "prefix={:02X}{:02X} year={} serial={} (id 0x{})",
+        bytes[0],
+        bytes[1],
+        bytes[2],
+        u32::from_be_bytes([0, bytes[3], bytes[4], bytes[5]]),
+        hex::encode(&bytes[2..])
+    )
+}
+
+fn unknown(bytes: &[u8]) -> String {
+    if bytes.iter().all(|b| b.is_ascii_graphic()) {
+        format!("\"{}\"", String::from_utf8_lossy(bytes))
+    } else {
+        String::new()
+    }
+}
+
+impl fmt::Display for Inspection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Input:  {:?}", self.input)?;
+        writeln!(f, "Frame:  {}", hex::encode(&self.frame))?;
+        match self.crc {
+            CrcStatus::Valid => writeln!(f, "CRC:    valid")?,
+            CrcStatus::Invalid { computed, stored } => writeln!(
+                f,
+                "CRC:    INVALID (computed 0x{:04X}, stored 0x{:04X})",
+                computed, stored
+            )?,
+            CrcStatus::Truncated { declared, actual } => writeln!(
+                f,
+                "CRC:    not checked, frame truncated ({} of {} bytes)",
+                actual, declared
+            )?,
+        }
+        writeln!(f)?;
+        writeln!(f, "{:<7} {:<24} {:<14} Value", "Offset", "Bytes", "Field")?;
+        for field in &self.fields {
+            let mut bytes = hex::encode(&field.bytes);
+            if bytes.len() > 24 {
+                bytes.truncate(21);
+                bytes.push_str("...");
+            }
+            writeln!(
+                f,
+                "{:<7} {:<24} {:<14} {}",
+                field.offset, bytes, field.name, field.value
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::radian::make_radian_master_req;
+
+    fn field<'a>(inspection: &'a Inspection, name: &str) -> &'a Field {
+        inspection.fields.iter().find(|f| f.name == name).unwrap()
+    }
+
+    #[test]
+    fn inspect_encoded_request() {
+        let inspection = inspect(&make_radian_master_req(16, 1087372));
+        assert_eq!(inspection.input, InputKind::Encoded);
+        assert_eq!(inspection.crc, CrcStatus::Valid);
+        assert_eq!(
+            field(&inspection, "Destination").value,
+            "prefix=0045 year=16 serial=1087372 (id 0x1010978c)"
+        );
+        assert_eq!(field(&inspection, "Command").bytes, vec![0x00, 0x0A, 0x40]);
+    }
+
+    #[test]
+    fn inspect_garbage_returns() {
+        // bad stop bits all along, and longer than a u16 byte count
+        let inspection = inspect(&[0x0F; 70_000]);
+        assert_eq!(inspection.input, InputKind::Oversampled);
+
+        let inspection = inspect(&[0x00, 0xF0, 0x0F, 0xAA]);
+        assert_eq!(inspection.input, InputKind::Oversampled);
+    }
+
+    #[test]
+    fn inspect_decoded_response() {
+        let frame = hex::decode("7c110045200a501400450e0f94f800010f0050e51000400615011a03100d232e3032323033304242343100000612040141040000000000008080808080808080808080808080d8850f00ec9c0f0012b10f003ecc0f0026e70f007d021000c51f1000c54410006d6e1000fb85100053a11000eaba100035d31000f0").unwrap();
+        let inspection = inspect(&frame);
+        assert_eq!(inspection.input, InputKind::Decoded);
+        assert_eq!(
+            inspection.crc,
+            CrcStatus::Truncated {
+                declared: 124,
+                actual: 123
+            }
+        );
+        assert_eq!(
+            field(&inspection, "Source").value,
+            "prefix=0045 year=14 serial=1021176 (id 0x0e0f94f8)"
+        );
+        assert_eq!(field(&inspection, "Index").value, "1107280 L");
+        assert_eq!(field(&inspection, "Battery left").value, "46 months");
+        assert_eq!(field(&inspection, "Wake hour").value, "06:00");
+        assert_eq!(field(&inspection, "Sleep hour").value, "18:00");
+        assert_eq!(field(&inspection, "Reads counter").value, "65");
+        assert!(field(&inspection, "Index history")
+            .value
+            .starts_with("1017304 L, "));
+    }
+}
@@ -0,0 +1,188 @@
+//! Leak detection rules over the reading history.
+
+use crate::config::{LeakDetectionConfig, NightFlowConfig};
+use crate::history::Reading;
+use chrono::{DateTime, Duration, NaiveTime, TimeZone};
+use serde::Serialize;
+
+#[derive(Debug, Default, Serialize)]
+pub struct LeakStatus {
+    pub leak: bool,
+    pub leak_reasons: Vec<String>,
+}
+
+/// Run every configured rule over the persisted readings, newest last.
+pub fn detect<Tz: TimeZone>(
+    config: &LeakDetectionConfig,
+    readings: &[Reading],
+    now: &DateTime<Tz>,
+) -> LeakStatus {
+    let reasons: Vec<String> = [
+        config
+            .continuous_flow_reads
+            .and_then(|reads| continuous_flow(readings, reads as usize)),
+        config
+            .night_flow
+            .as_ref()
+            .and_then(|night_flow| excessive_night_flow(night_flow, readings, now)),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    LeakStatus {
+        leak: !reasons.is_empty(),
+        leak_reasons: reasons,
+    }
+}
+
+// Consumption never dropped to zero between any of the last `reads` reads
+fn continuous_flow(readings: &[Reading], reads: usize) -> Option<String> {
+    if readings.len() <= reads {
+        return None;
+    }
+
+    let window = &readings[readings.len() - reads - 1..];
+    if window
+        .windows(2)
+        .all(|pair| pair[1].liters > pair[0].liters)
+    {
+        Some(format!(
+            "Consumption never dropped to zero across the last {} reads ({} L since {})",
+            reads,
+            window[reads].liters - window[0].liters,
+            window[0].timestamp.to_rfc3339()
+        ))
+    } else {
+        None
+    }
+}
+
+// Average flow between reads taken within the night window over the last 24 hours
+fn excessive_night_flow<Tz: TimeZone>(
+    config: &NightFlowConfig,
+    readings: &[Reading],
+    now: &DateTime<Tz>,
+) -> Option<String> {
+    let start = NaiveTime::from_hms_opt(config.start_hour, 0, 0)?;
+    let end = NaiveTime::from_hms_opt(config.end_hour, 0, 0)?;
+    let in_night = |reading: &Reading| {
+        let time = reading.timestamp.with_timezone(&now.timezone()).time();
+        if start <= end {
+            start <= time && time <= end
+        } else {
+            start <= time || time <= end
+        }
+    };
+    let night_length = (end - start + Duration::days(1)).num_seconds() % 86400;
+    let since = now.to_utc() - Duration::days(1);
+
+    let (liters, seconds) = readings
+        .windows(2)
+        .filter(|pair| pair[0].timestamp >= since && in_night(&pair[0]) && in_night(&pair[1]))
+        .map(|pair| {
+            (
+                pair[1].timestamp - pair[0].timestamp,
+                pair[1].liters - pair[0].liters,
+            )
+        })
+        .filter(|(elapsed, _)| elapsed.num_seconds() <= night_length)
+        .fold((0, 0), |(liters, seconds), (elapsed, delta)| {
+            (liters + delta, seconds + elapsed.num_seconds())
+        });
+    if seconds == 0 {
+        return None;
+    }
+
+    let flow = liters as f64 * 3600.0 / seconds as f64;
+    if flow > config.threshold {
+        Some(format!(
+            "Average flow of {:.1} L/h between {:02}:00 and {:02}:00 exceeds {:.1} L/h",
+            flow, config.start_hour, config.end_hour, config.threshold
+        ))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+    }
+
+    fn readings(readings: &[(&str, i64)]) -> Vec<Reading> {
+        readings
+            .iter()
+            .map(|&(timestamp, liters)| Reading {
+                timestamp: at(timestamp),
+                liters,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn continuous_flow_rule() {
+        let config = LeakDetectionConfig {
+            continuous_flow_reads: Some(3),
+            night_flow: None,
+        };
+        let now = at("2026-03-10T12:00:00Z");
+
+        let leaking = readings(&[
+            ("2026-03-10T08:00:00Z", 100),
+            ("2026-03-10T09:00:00Z", 101),
+            ("2026-03-10T10:00:00Z", 102),
+            ("2026-03-10T11:00:00Z", 110),
+        ]);
+        let status = detect(&config, &leaking, &now);
+        assert!(status.leak);
+        assert_eq!(status.leak_reasons.len(), 1);
+
+        let idle = readings(&[
+            ("2026-03-10T08:00:00Z", 100),
+            ("2026-03-10T09:00:00Z", 101),
+            ("2026-03-10T10:00:00Z", 101),
+            ("2026-03-10T11:00:00Z", 110),
+        ]);
+        assert!(!detect(&config, &idle, &now).leak);
+
+        // Not enough reads to conclude
+        assert!(!detect(&config, &leaking[1..], &now).leak);
+    }
+
+    #[test]
+    fn night_flow_rule() {
+        let config = LeakDetectionConfig {
+            continuous_flow_reads: None,
+            night_flow: Some(NightFlowConfig {
+                start_hour: 23,
+                end_hour: 5,
+                threshold: 5.0,
+            }),
+        };
+        let now = at("2026-03-10T07:00:00Z");
+
+        let leaking = readings(&[
+            ("2026-03-09T20:00:00Z", 0),
+            ("2026-03-09T23:00:00Z", 200),
+            ("2026-03-10T03:00:00Z", 240),
+            ("2026-03-10T07:00:00Z", 500),
+        ]);
+        let status = detect(&config, &leaking, &now);
+        assert!(status.leak);
+        assert_eq!(
+            status.leak_reasons,
+            vec!["Average flow of 10.0 L/h between 23:00 and 05:00 exceeds 5.0 L/h"]
+        );
+
+        let quiet = readings(&[("2026-03-09T23:00:00Z", 200), ("2026-03-10T03:00:00Z", 204)]);
+        assert!(!detect(&config, &quiet, &now).leak);
+
+        // Reads more than a day old are ignored
+        assert!(!detect(&config, &leaking, &at("2026-03-11T07:00:00Z")).leak);
+    }
+}
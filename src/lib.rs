@@ -0,0 +1,72 @@
+//! Read Itron EverBlu Cyble water meters over the RADIAN protocol with a CC1101 transceiver.
+//!
+//! - [`radian`]: frame encoding and decoding, requests, CRC, and the [`MeterData`] report
+//! - `cc1101`: driver for the CC1101, over rppal on a Raspberry Pi or Linux spidev/gpiochip (`bus`)
+//! - `exchange`: the request/ack/data exchange with a meter over any `RadianLink`
+//! - `mqtt`: Home Assistant discovery and state publishing
+//! - `reader`: a whole read of the configured meter, from the duty cycle to the state published
+//!
+//! Only [`radian`] is available without the default `std` feature, for microcontrollers.
+//! Its functions returning a `Vec` need the `alloc` feature.
+//!
+//! ```
+//! # #[cfg(feature = "alloc")] {
+//! use hass_everblu_meter::radian::{decode_serial_1_3, make_radian_master_req, SYNCH_PATTERN};
+//!
+//! let request = make_radian_master_req(16, 1087372);
+//! let frame = decode_serial_1_3(&request[SYNCH_PATTERN.len()..]);
+//! assert_eq!(frame[0] as usize, frame.len());
+//! # }
+//! ```
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "alloc")]
+#[macro_use]
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod airtime;
+#[cfg(feature = "std")]
+pub mod battery;
+#[cfg(feature = "std")]
+pub mod bus;
+#[cfg(feature = "std")]
+pub mod capture;
+#[cfg(feature = "std")]
+pub mod cc1101;
+#[cfg(feature = "std")]
+mod cc1101_const;
+#[cfg(feature = "std")]
+pub mod config;
+#[cfg(all(test, feature = "std"))]
+mod emulator;
+#[cfg(feature = "std")]
+pub mod exchange;
+#[cfg(feature = "std")]
+pub mod fs_util;
+#[cfg(feature = "std")]
+pub mod history;
+#[cfg(feature = "std")]
+pub mod inspect;
+#[cfg(feature = "std")]
+pub mod leak;
+#[cfg(feature = "std")]
+pub mod mqtt;
+#[cfg(feature = "std")]
+pub mod profile;
+pub mod radian;
+#[cfg(feature = "std")]
+pub mod reader;
+#[cfg(feature = "std")]
+pub mod scan;
+#[cfg(feature = "std")]
+pub mod schedule;
+#[cfg(feature = "std")]
+pub mod sniff;
+#[cfg(feature = "std")]
+pub mod tune;
+
+#[cfg(feature = "std")]
+pub use cc1101::CC1101;
+pub use radian::MeterData;
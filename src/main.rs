@@ -1,15 +1,14 @@
-mod cc1101;
-mod cc1101_const;
-mod config;
-mod mqtt;
-mod radian;
-
-use cc1101::{MeterData, CC1101};
-use config::Config;
-use log::{error, info, LevelFilter};
-use mqtt::MqttPublisher;
+use hass_everblu_meter::capture::{Capture, FrameKind};
+use hass_everblu_meter::config::Config;
+use hass_everblu_meter::mqtt::MqttPublisher;
+use hass_everblu_meter::profile::RegisterProfile;
+use hass_everblu_meter::scan::{sweep_frequencies, Spectrum, SpectrumPoint};
+use hass_everblu_meter::tune;
+use hass_everblu_meter::{bus, fs_util, inspect, reader, sniff, CC1101};
+use log::{debug, error, info, warn, LevelFilter};
 use simple_logger::SimpleLogger;
 use std::process::exit;
+use std::time::Duration;
 
 fn main() {
     SimpleLogger::new()
@@ -18,7 +17,7 @@ fn main() {
         .init()
         .unwrap();
 
-    match run() {
+    match run(parse_args()) {
         Ok(_) => exit(0),
         Err(e) => {
             error!("Process failed with: {}", e);
@@ -27,47 +26,270 @@ fn main() {
     }
 }
 
-fn run() -> Result<(), Box<dyn std::error::Error>> {
-    let config = load_config()?;
+enum Command {
+    Read { config_path: String },
+    Poll { config_path: String },
+    Replay { capture_paths: Vec<String> },
+    Decode { frame_hex: String },
+    Sniff { config_path: String },
+    Selftest { config_path: String },
+    Scan { config_path: String },
+    Tune { config_path: String },
+}
+
+fn parse_args() -> Command {
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("poll") => Command::Poll {
+            config_path: args.next().unwrap_or_else(|| "config.toml".to_string()),
+        },
+        Some("replay") => Command::Replay {
+            capture_paths: args.collect(),
+        },
+        Some("decode") => Command::Decode {
+            frame_hex: args.collect(),
+        },
+        Some("sniff") => Command::Sniff {
+            config_path: args.next().unwrap_or_else(|| "config.toml".to_string()),
+        },
+        Some("selftest") => Command::Selftest {
+            config_path: args.next().unwrap_or_else(|| "config.toml".to_string()),
+        },
+        Some("scan") => Command::Scan {
+            config_path: args.next().unwrap_or_else(|| "config.toml".to_string()),
+        },
+        Some("tune") => Command::Tune {
+            config_path: args.next().unwrap_or_else(|| "config.toml".to_string()),
+        },
+        config_path => Command::Read {
+            config_path: config_path.unwrap_or("config.toml").to_string(),
+        },
+    }
+}
+
+fn run(command: Command) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::Read { config_path } => read_meter(&config_path),
+        Command::Poll { config_path } => poll(&config_path),
+        Command::Replay { capture_paths } => replay(&capture_paths),
+        Command::Decode { frame_hex } => decode(&frame_hex),
+        Command::Sniff { config_path } => sniff(&config_path),
+        Command::Selftest { config_path } => selftest(&config_path),
+        Command::Scan { config_path } => scan(&config_path),
+        Command::Tune { config_path } => tune(&config_path),
+    }
+}
+
+fn read_meter(config_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_config(config_path)?;
+
+    let cc1101 = open_radio(&config)?;
+    let state = reader::read_meter(&config, &cc1101)?;
 
-    info!("Initializing cc1101 device");
-    let cc1101 = CC1101::new();
-    info!(
-        "Reading meter serial={} year={}",
-        config.meter.serial, config.meter.year
-    );
-    let meter_data = cc1101.get_meter_data(config.meter.year, config.meter.serial)?;
-    info!("Meter data read successfully:\n{:?}", meter_data);
     info!(
         "Publishing sensor to mqtt broker {}",
         config.mqtt.broker_url
     );
-    publish_to_mqtt(&config, &meter_data)
+    reader::publish(&config, &state)?;
+
+    Ok(())
 }
 
-fn load_config() -> Result<Config, Box<dyn std::error::Error>> {
-    let config_path = std::env::args()
-        .nth(1)
-        .unwrap_or_else(|| "config.toml".to_string());
+fn poll(config_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_config(config_path)?;
 
-    info!("Loading configuration from: {}", config_path);
-    Config::load(&config_path).map_err(Into::into)
+    let mut publisher = MqttPublisher::new(config.mqtt.clone(), config.homeassistant.clone())?;
+    publisher.run_in_background();
+    publisher.publish_discovery(&config.meter, config.leak_detection.is_some())?;
+
+    let cc1101 = open_radio(&config)?;
+    let interval = Duration::from_secs(config.poll.interval_minutes as u64 * 60);
+    info!(
+        "Reading the meter every {} minutes, press Ctrl-C to stop",
+        config.poll.interval_minutes
+    );
+    loop {
+        // a failed read is retried at the next interval, the history keeps the leak rules going
+        match reader::read_meter(&config, &cc1101) {
+            Ok(state) => {
+                if let Err(e) = publisher.publish_state(&state.payload()) {
+                    warn!("{}", e);
+                }
+            }
+            Err(e) => warn!("Read failed: {}", e),
+        }
+        std::thread::sleep(interval);
+    }
 }
 
-fn publish_to_mqtt(
-    config: &Config,
-    meter_data: &MeterData,
-) -> Result<(), Box<dyn std::error::Error>> {
+fn replay(capture_paths: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    if capture_paths.is_empty() {
+        return Err("Usage: hass-everblu-meter replay <capture.json>...".into());
+    }
+
+    for capture_path in capture_paths {
+        let capture = Capture::load(capture_path)?;
+        info!(
+            "Replaying {} (serial={} year={} captured at {})",
+            capture_path, capture.serial, capture.year, capture.timestamp
+        );
+
+        for frame in &capture.frames {
+            info!(
+                "{:?} frame: bytes={} rssi={} lqi={} F_est={}",
+                frame.kind,
+                frame.byte_count,
+                frame.link_quality.rssi_dbm,
+                frame.link_quality.lqi,
+                frame.link_quality.freq_est
+            );
+            if frame.byte_count == 0 {
+                continue;
+            }
+
+            info!("Decoded: {}", hex::encode(frame.decode()));
+            if frame.kind == FrameKind::Data {
+                match frame.replay() {
+                    Ok(meter_data) => info!("Meter data:\n{:?}", meter_data),
+                    Err(e) => warn!("Meter data not parsed: {}", e),
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn decode(frame_hex: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let frame_hex: String = frame_hex
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != ':')
+        .collect();
+    if frame_hex.is_empty() {
+        return Err("Usage: hass-everblu-meter decode <hex frame>".into());
+    }
+
+    let frame = hex::decode(&frame_hex)?;
+    print!("{}", inspect::inspect(&frame));
+
+    Ok(())
+}
+
+fn sniff(config_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_config(config_path)?;
+
     let mut publisher = MqttPublisher::new(config.mqtt.clone(), config.homeassistant.clone())?;
+    publisher.run_in_background();
+
+    let cc1101 = open_radio(&config)?;
+    info!("Listening for RADIAN traffic, press Ctrl-C to stop");
+    loop {
+        let Some((rx_buffer, link_quality)) = cc1101.sniff_frame(5000) else {
+            continue;
+        };
+        let Some(frame) = sniff::identify(&rx_buffer, link_quality) else {
+            debug!("Ignored {} bytes of noise", rx_buffer.len());
+            continue;
+        };
+
+        info!(
+            "Heard {:?} for meter serial={} year={} crc_valid={} rssi={}: {}",
+            frame.kind,
+            frame.serial,
+            frame.year,
+            frame
+                .crc_valid
+                .map_or("unknown".to_string(), |valid| valid.to_string()),
+            frame.link_quality.rssi_dbm,
+            hex::encode(&frame.payload)
+        );
+        if let Err(e) = publisher.publish_sniffed(&frame) {
+            warn!("{}", e);
+        }
+    }
+}
 
-    info!("Publishing Home Assistant discovery messages");
-    publisher.publish_discovery(&config.meter)?;
+fn selftest(config_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_config(config_path)?;
 
-    info!("Publishing meter state");
-    publisher.publish_state(meter_data)?;
+    open_radio(&config)?;
+    info!("Radio self-test passed");
 
-    // Wait for messages to be transmitted before exiting
-    publisher.disconnect();
+    Ok(())
+}
+
+fn scan(config_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_config(config_path)?;
+
+    let cc1101 = open_radio(&config)?;
+    let carrier_hz = cc1101.carrier_hz();
+    let frequencies = sweep_frequencies(carrier_hz, config.scan.span_khz, config.scan.step_khz);
+    info!(
+        "Scanning {} kHz around {:.3} MHz in {} kHz steps",
+        config.scan.span_khz,
+        carrier_hz as f64 / 1e6,
+        config.scan.step_khz
+    );
+    let levels = cc1101.scan(&frequencies);
+    let points = frequencies
+        .into_iter()
+        .zip(levels)
+        .map(|(frequency_hz, rssi_dbm)| SpectrumPoint {
+            frequency_hz,
+            rssi_dbm,
+        })
+        .collect();
+    let spectrum = Spectrum::new(carrier_hz, points);
+    print!("{}", spectrum.render());
+
+    if config.scan.publish {
+        let mut publisher = MqttPublisher::new(config.mqtt.clone(), config.homeassistant.clone())?;
+        publisher.publish_scan(&config.meter, &spectrum)?;
+        publisher.disconnect();
+    }
 
     Ok(())
 }
+
+fn tune(config_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_config(config_path)?;
+    // found out before the reads rather than after spending their airtime
+    fs_util::check_writable(config_path).map_err(|e| {
+        format!(
+            "Tuning stores its result in {}, which cannot be written: {}",
+            config_path, e
+        )
+    })?;
+
+    let mut cc1101 = open_radio(&config)?;
+    info!(
+        "Tuning the receiver on meter serial={} year={}, {} reads per setting",
+        config.meter.serial,
+        config.meter.year,
+        tune::TUNE_READS
+    );
+    let tuning = reader::tune_receiver(&config, &mut cc1101)?;
+
+    info!(
+        "Tuned: frequency error {} Hz (spread {} Hz), {} kHz RX filter, data rate offset {}",
+        tuning.freq_offset_hz,
+        tuning.freq_spread_hz,
+        tuning.bandwidth_hz / 1000,
+        tuning.data_rate_offset
+    );
+    Config::store_radio_profile(config_path, &tuning.overrides())?;
+    info!("Tuned registers stored in {}", config_path);
+
+    Ok(())
+}
+
+fn open_radio(config: &Config) -> Result<CC1101, Box<dyn std::error::Error>> {
+    let profile = RegisterProfile::load(&config.radio, &config.radio_profile)?;
+    info!("Initializing cc1101 device");
+    Ok(CC1101::new(bus::open(&config.radio.backend)?, profile)?)
+}
+
+fn load_config(config_path: &str) -> Result<Config, Box<dyn std::error::Error>> {
+    info!("Loading configuration from: {}", config_path);
+    Config::load(config_path).map_err(Into::into)
+}
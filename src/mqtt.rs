@@ -1,5 +1,13 @@
-use crate::cc1101::MeterData;
+//! Home Assistant MQTT discovery and state publishing.
+
+use crate::battery::BatteryStatus;
 use crate::config::{HomeAssistantConfig, MeterConfig, MqttConfig};
+use crate::history::Consumption;
+use crate::leak::LeakStatus;
+use crate::radian::MeterData;
+use crate::scan::Spectrum;
+use crate::schedule::ListeningSchedule;
+use crate::sniff::SniffedFrame;
 use log::{debug, error, info};
 use rumqttc::{Client, Connection, MqttOptions, QoS};
 use serde::Serialize;
@@ -35,6 +43,8 @@ struct DeviceInfo<'a> {
 
 #[derive(Serialize)]
 struct DiscoveryConfig<'a> {
+    #[serde(skip)]
+    component: &'static str,
     name: &'static str,
     unique_id: String,
     object_id: &'static str,
@@ -43,16 +53,36 @@ struct DiscoveryConfig<'a> {
     icon: &'static str,
     device: &'a DeviceInfo<'a>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    entity_category: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     unit_of_measurement: Option<&'static str>,
     #[serde(skip_serializing_if = "Option::is_none")]
     state_class: Option<&'static str>,
     #[serde(skip_serializing_if = "Option::is_none")]
     device_class: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    json_attributes_topic: Option<&'a String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    json_attributes_template: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+pub struct StatePayload<'a> {
+    #[serde(flatten)]
+    pub meter_data: &'a MeterData,
+    #[serde(flatten)]
+    pub consumption: &'a Consumption,
+    #[serde(flatten)]
+    pub battery_status: &'a BatteryStatus,
+    #[serde(flatten)]
+    pub listening_schedule: &'a ListeningSchedule,
+    #[serde(flatten)]
+    pub leak_status: Option<&'a LeakStatus>,
 }
 
 pub struct MqttPublisher {
     client: Client,
-    connection: Connection,
+    connection: Option<Connection>, // None once handed over to a background thread
     mqtt_config: MqttConfig,
     ha_config: HomeAssistantConfig,
 }
@@ -76,13 +106,17 @@ impl MqttPublisher {
 
         Ok(Self {
             client,
-            connection,
+            connection: Some(connection),
             mqtt_config,
             ha_config,
         })
     }
 
-    pub fn publish_discovery(&self, meter_config: &MeterConfig) -> Result<(), MqttError> {
+    pub fn publish_discovery(
+        &self,
+        meter_config: &MeterConfig,
+        leak_detection: bool,
+    ) -> Result<(), MqttError> {
         let device_info = self.create_device_info(meter_config);
         let state_topic = format!(
             "{}/sensor/{}/state",
@@ -91,8 +125,9 @@ impl MqttPublisher {
         let unique_id =
             |object_id: &str| format!("water_meter_{}_{}", meter_config.serial, object_id);
 
-        let sensors = vec![
+        let mut sensors = vec![
             DiscoveryConfig {
+                component: "sensor",
                 name: "Water Consumption",
                 unique_id: unique_id("water_consumption"),
                 object_id: "water_consumption",
@@ -100,11 +135,17 @@ impl MqttPublisher {
                 value_template: "{{ value_json.liters }}",
                 icon: "mdi:water",
                 device: &device_info,
+                entity_category: None,
                 unit_of_measurement: Some("L"),
                 state_class: Some("total_increasing"),
                 device_class: Some("water"),
+                json_attributes_topic: Some(&state_topic),
+                json_attributes_template: Some(
+                    "{{ {'raw_liters': value_json.raw_liters} | tojson }}",
+                ),
             },
             DiscoveryConfig {
+                component: "sensor",
                 name: "Battery Life",
                 object_id: "battery",
                 unique_id: unique_id("battery"),
@@ -112,11 +153,15 @@ impl MqttPublisher {
                 value_template: "{{ value_json.battery_left }}",
                 icon: "mdi:battery",
                 device: &device_info,
+                entity_category: None,
                 unit_of_measurement: Some("months"),
                 state_class: None,
                 device_class: None,
+                json_attributes_topic: None,
+                json_attributes_template: None,
             },
             DiscoveryConfig {
+                component: "sensor",
                 name: "Read Counter",
                 unique_id: unique_id("reads_counter"),
                 object_id: "reads_counter",
@@ -124,40 +169,187 @@ impl MqttPublisher {
                 value_template: "{{ value_json.reads_counter }}",
                 icon: "mdi:counter",
                 device: &device_info,
+                entity_category: None,
                 unit_of_measurement: Some("reads"),
                 state_class: Some("total_increasing"),
                 device_class: None,
+                json_attributes_topic: None,
+                json_attributes_template: None,
             },
             DiscoveryConfig {
+                component: "sensor",
                 name: "Wake Time",
                 unique_id: unique_id("wake_time"),
                 object_id: "wake_time",
                 state_topic: &state_topic,
-                value_template: "{{ value_json.time_start }}",
+                value_template: "{{ '%02d:00' | format(value_json.time_start) }}",
                 icon: "mdi:clock-start",
                 device: &device_info,
-                unit_of_measurement: Some("hour"),
+                entity_category: None,
+                unit_of_measurement: None,
                 state_class: None,
                 device_class: None,
+                json_attributes_topic: None,
+                json_attributes_template: None,
             },
             DiscoveryConfig {
+                component: "sensor",
                 name: "Sleep Time",
                 unique_id: unique_id("sleep_time"),
                 object_id: "sleep_time",
                 state_topic: &state_topic,
-                value_template: "{{ value_json.time_end }}",
+                value_template: "{{ '%02d:00' | format(value_json.time_end) }}",
                 icon: "mdi:clock-end",
                 device: &device_info,
-                unit_of_measurement: Some("hour"),
+                entity_category: None,
+                unit_of_measurement: None,
+                state_class: None,
+                device_class: None,
+                json_attributes_topic: None,
+                json_attributes_template: None,
+            },
+            DiscoveryConfig {
+                component: "sensor",
+                name: "Listening Schedule",
+                unique_id: unique_id("listening_schedule"),
+                object_id: "listening_schedule",
+                state_topic: &state_topic,
+                value_template: "{{ value_json.listening_schedule }}",
+                icon: "mdi:calendar-clock",
+                device: &device_info,
+                entity_category: None,
+                unit_of_measurement: None,
                 state_class: None,
                 device_class: None,
+                json_attributes_topic: None,
+                json_attributes_template: None,
+            },
+            DiscoveryConfig {
+                component: "sensor",
+                name: "Consumption Since Last Read",
+                unique_id: unique_id("consumption_since_last_read"),
+                object_id: "consumption_since_last_read",
+                state_topic: &state_topic,
+                value_template: "{{ value_json.consumption_since_last_read | default(none) }}",
+                icon: "mdi:water-plus",
+                device: &device_info,
+                entity_category: None,
+                unit_of_measurement: Some("L"),
+                state_class: Some("measurement"),
+                device_class: None,
+                json_attributes_topic: None,
+                json_attributes_template: None,
+            },
+            DiscoveryConfig {
+                component: "sensor",
+                name: "Flow Rate",
+                unique_id: unique_id("flow_rate"),
+                object_id: "flow_rate",
+                state_topic: &state_topic,
+                value_template: "{{ value_json.flow_rate | default(none) }}",
+                icon: "mdi:waves-arrow-right",
+                device: &device_info,
+                entity_category: None,
+                unit_of_measurement: Some("L/h"),
+                state_class: Some("measurement"),
+                device_class: Some("volume_flow_rate"),
+                json_attributes_topic: None,
+                json_attributes_template: None,
+            },
+            DiscoveryConfig {
+                component: "sensor",
+                name: "Consumption Today",
+                unique_id: unique_id("consumption_today"),
+                object_id: "consumption_today",
+                state_topic: &state_topic,
+                value_template: "{{ value_json.consumption_today }}",
+                icon: "mdi:water",
+                device: &device_info,
+                entity_category: None,
+                unit_of_measurement: Some("L"),
+                state_class: Some("total_increasing"),
+                device_class: Some("water"),
+                json_attributes_topic: None,
+                json_attributes_template: None,
+            },
+            DiscoveryConfig {
+                component: "sensor",
+                name: "Consumption This Month",
+                unique_id: unique_id("consumption_this_month"),
+                object_id: "consumption_this_month",
+                state_topic: &state_topic,
+                value_template: "{{ value_json.consumption_this_month }}",
+                icon: "mdi:water",
+                device: &device_info,
+                entity_category: None,
+                unit_of_measurement: Some("L"),
+                state_class: Some("total_increasing"),
+                device_class: Some("water"),
+                json_attributes_topic: None,
+                json_attributes_template: None,
+            },
+            DiscoveryConfig {
+                component: "binary_sensor",
+                name: "Battery Low",
+                unique_id: unique_id("battery_low"),
+                object_id: "battery_low",
+                state_topic: &state_topic,
+                value_template: "{{ 'ON' if value_json.battery_low else 'OFF' }}",
+                icon: "mdi:battery-alert",
+                device: &device_info,
+                entity_category: None,
+                unit_of_measurement: None,
+                state_class: None,
+                device_class: Some("battery"),
+                json_attributes_topic: None,
+                json_attributes_template: None,
+            },
+            DiscoveryConfig {
+                component: "sensor",
+                name: "Battery End of Life",
+                unique_id: unique_id("battery_end_of_life"),
+                object_id: "battery_end_of_life",
+                state_topic: &state_topic,
+                value_template: "{{ value_json.battery_end_of_life }}",
+                icon: "mdi:battery-clock",
+                device: &device_info,
+                entity_category: None,
+                unit_of_measurement: None,
+                state_class: None,
+                device_class: Some("timestamp"),
+                json_attributes_topic: None,
+                json_attributes_template: None,
             },
         ];
 
+        if leak_detection {
+            sensors.push(DiscoveryConfig {
+                component: "binary_sensor",
+                name: "Leak",
+                unique_id: unique_id("leak"),
+                object_id: "leak",
+                state_topic: &state_topic,
+                value_template: "{{ 'ON' if value_json.leak else 'OFF' }}",
+                icon: "mdi:water-alert",
+                device: &device_info,
+                entity_category: None,
+                unit_of_measurement: None,
+                state_class: None,
+                device_class: Some("problem"),
+                json_attributes_topic: Some(&state_topic),
+                json_attributes_template: Some(
+                    "{{ {'reasons': value_json.leak_reasons} | tojson }}",
+                ),
+            });
+        }
+
         for sensor in sensors {
             let config_topic = format!(
-                "{}/sensor/{}/{}/config",
-                self.ha_config.discovery_prefix, self.ha_config.node_id, sensor.object_id
+                "{}/{}/{}/{}/config",
+                self.ha_config.discovery_prefix,
+                sensor.component,
+                self.ha_config.node_id,
+                sensor.object_id
             );
             let payload = serde_json::to_string(&sensor)
                 .map_err(|e| MqttError::PublishError(format!("JSON serialization error: {}", e)))?;
@@ -169,13 +361,13 @@ impl MqttPublisher {
         Ok(())
     }
 
-    pub fn publish_state(&self, meter_data: &MeterData) -> Result<(), MqttError> {
+    pub fn publish_state(&self, state: &StatePayload) -> Result<(), MqttError> {
         let state_topic = format!(
             "{}/sensor/{}/state",
             self.ha_config.discovery_prefix, self.ha_config.node_id
         );
 
-        let payload = serde_json::to_string(&meter_data)
+        let payload = serde_json::to_string(state)
             .map_err(|e| MqttError::PublishError(format!("JSON serialization error: {}", e)))?;
 
         self.publish(&state_topic, &payload, self.mqtt_config.retain)?;
@@ -184,11 +376,94 @@ impl MqttPublisher {
         Ok(())
     }
 
+    /// Publish a frame heard in sniff mode, not retained.
+    pub fn publish_sniffed(&self, frame: &SniffedFrame) -> Result<(), MqttError> {
+        let sniffed_topic = format!(
+            "{}/sensor/{}/sniffed",
+            self.ha_config.discovery_prefix, self.ha_config.node_id
+        );
+
+        let payload = serde_json::to_string(frame)
+            .map_err(|e| MqttError::PublishError(format!("JSON serialization error: {}", e)))?;
+
+        self.publish(&sniffed_topic, &payload, false)
+    }
+
+    /// Publish the noise floor of a scan as a diagnostic sensor, with the spectrum as attributes.
+    pub fn publish_scan(
+        &self,
+        meter_config: &MeterConfig,
+        spectrum: &Spectrum,
+    ) -> Result<(), MqttError> {
+        let device_info = self.create_device_info(meter_config);
+        let scan_topic = format!(
+            "{}/sensor/{}/scan",
+            self.ha_config.discovery_prefix, self.ha_config.node_id
+        );
+        let sensor = DiscoveryConfig {
+            component: "sensor",
+            name: "Noise Floor",
+            unique_id: format!("water_meter_{}_noise_floor", meter_config.serial),
+            object_id: "noise_floor",
+            state_topic: &scan_topic,
+            value_template: "{{ value_json.noise_floor_dbm }}",
+            icon: "mdi:radio-tower",
+            device: &device_info,
+            entity_category: Some("diagnostic"),
+            unit_of_measurement: Some("dBm"),
+            state_class: Some("measurement"),
+            device_class: Some("signal_strength"),
+            json_attributes_topic: Some(&scan_topic),
+            json_attributes_template: Some(
+                "{{ {'carrier_hz': value_json.carrier_hz, 'peak': value_json.peak, 'points': value_json.points} | tojson }}",
+            ),
+        };
+        let config_topic = format!(
+            "{}/{}/{}/{}/config",
+            self.ha_config.discovery_prefix,
+            sensor.component,
+            self.ha_config.node_id,
+            sensor.object_id
+        );
+        let payload = serde_json::to_string(&sensor)
+            .map_err(|e| MqttError::PublishError(format!("JSON serialization error: {}", e)))?;
+        self.publish(&config_topic, &payload, true)?;
+
+        let payload = serde_json::to_string(spectrum)
+            .map_err(|e| MqttError::PublishError(format!("JSON serialization error: {}", e)))?;
+        self.publish(&scan_topic, &payload, self.mqtt_config.retain)?;
+        info!("Published scan");
+
+        Ok(())
+    }
+
+    /// Drive the connection from a background thread, for long-running modes
+    /// where publishing happens long after the publisher is created.
+    pub fn run_in_background(&mut self) {
+        if let Some(mut connection) = self.connection.take() {
+            std::thread::spawn(move || {
+                for notification in connection.iter() {
+                    match notification {
+                        Ok(notif) => debug!("mqtt: {notif:?}"),
+                        Err(error) => {
+                            // The next iteration reconnects
+                            error!("mqtt: {error:?}");
+                            std::thread::sleep(Duration::from_secs(5));
+                        }
+                    }
+                }
+            });
+        }
+    }
+
     // /// Wait for pending MQTT messages to be transmitted before exiting
     // /// This is critical for one-shot programs that exit immediately after publishing
     pub fn disconnect(&mut self) {
         self.client.disconnect().unwrap();
-        for notification in self.connection.iter() {
+        let Some(connection) = self.connection.as_mut() else {
+            return;
+        };
+        for notification in connection.iter() {
             match notification {
                 Ok(notif) => {
                     debug!("mqtt: {notif:?}")
@@ -0,0 +1,411 @@
+//! CC1101 register settings for each phase of a RADIAN exchange, with overrides from the
+//! configuration or a SmartRF Studio export.
+
+use crate::cc1101_const::*;
+use crate::config::{RadioConfig, RadioProfileConfig};
+use log::warn;
+use std::fs;
+
+/// Register writes, as (address, value) pairs.
+pub type Registers = Vec<(u8, u8)>;
+
+// The carrier is FREQ2..0 steps of f_XOSC / 2^16, about 397 Hz
+const XOSC_HZ: u64 = 26_000_000;
+
+/// PATABLE setting for each output power in dBm at 433 MHz, from the CC1101 datasheet.
+pub const PA_433MHZ: [(i8, u8); 8] = [
+    (-30, 0x12),
+    (-20, 0x0E),
+    (-15, 0x1D),
+    (-10, 0x34),
+    (0, 0x60),
+    (5, 0x84),
+    (7, 0xC8),
+    (10, 0xC0),
+];
+
+/// RSSI in dBm above which the channel is taken as busy, unless configured otherwise.
+pub const DEFAULT_LBT_THRESHOLD_DBM: i8 = -85;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisterProfile {
+    /// Written at startup and restored after each transmission or reception
+    pub base: Registers,
+    pub patable: [u8; 8],
+    /// Wake-up and request: no preamble nor sync word, infinite packet length
+    pub tx: Registers,
+    /// Length of the 0x55 wake-up sent before the request
+    pub wakeup_ms: u32,
+    /// RSSI above which the channel is busy and the transmission deferred
+    pub lbt_threshold_dbm: i8,
+    /// Search for the 2.4 kbps sync pattern starting a meter frame
+    pub sync_search: Registers,
+    /// Frame sampled 4 times per bit at 9.6 kbps
+    pub data: Registers,
+}
+
+#[derive(Debug)]
+pub enum ProfileError {
+    IoError(std::io::Error),
+    ParseError(String),
+}
+
+impl std::fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProfileError::IoError(e) => write!(f, "Failed to read SmartRF export: {}", e),
+            ProfileError::ParseError(e) => write!(f, "Failed to parse SmartRF export: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProfileError {}
+
+impl From<std::io::Error> for ProfileError {
+    fn from(err: std::io::Error) -> Self {
+        ProfileError::IoError(err)
+    }
+}
+
+impl Default for RegisterProfile {
+    fn default() -> Self {
+        //
+        // Rf settings for CC1101
+        //
+        RegisterProfile {
+            base: vec![
+                (IOCFG2, 0x01), //GDO2 Output Pin Configuration : Asserts when RX FIFO is filled at or above the RX FIFO threshold or the end of packet is reached, de-asserts when the RX FIFO is empty
+                (IOCFG0, 0x06), //GDO0 Output Pin Configuration : Asserts when sync word has been sent / received, and de-asserts at the end of the packet.
+                (FIFOTHR, 0x47), //0x4? adc with bandwith< 325khz ; TX FIFO threshold 33 bytes, RX FIFO threshold 32 bytes
+                (SYNC1, 0x55),   //01010101
+                (SYNC0, 0x00),   //00000000
+                //(PKTCTRL1,0x80),//Preamble quality estimator threshold=16  ; APPEND_STATUS=0; no addr check
+                (PKTCTRL1, 0x00), //Preamble quality estimator threshold=0   ; APPEND_STATUS=0; no addr check
+                (PKTCTRL0, 0x00), //fix length , no CRC
+                (FSCTRL1, 0x08),  //Frequency Synthesizer Control
+                (FREQ2, 0x10),    //Frequency Control Word, High Byte  Base frequency = 433.82
+                (FREQ1, 0xAF),    //Frequency Control Word, Middle Byte
+                (FREQ0, 0x75), //Frequency Control Word, Low Byte la fréquence reel etait 433.790 (centre)
+                //(FREQ0,0xC1), //Frequency Control Word, Low Byte rasmobo 814 824 (KO) ; minepi 810 820 (OK)
+                //(FREQ0,0x9B), //rasmobo 808.5  -16  pour -38
+                //(FREQ0,0xB7),   //rasmobo 810 819.5 OK
+                //mon compteur F1 : 433809500  F2 : 433820000   deviation +-5.25khz depuis 433.81475M
+                (MDMCFG4, 0xF6), //Modem Configuration   RX filter BW = 58Khz
+                (MDMCFG3, 0x83), //Modem Configuration   26M*((256+83h)*2^6)/2^28 = 2.4kbps
+                (MDMCFG2, 0x02), //Modem Configuration   2-FSK;  no Manchester ; 16/16 sync word bits detected
+                (MDMCFG1, 0x00), //Modem Configuration num preamble 2=>0 , Channel spacing_exp
+                (MDMCFG0, 0x00), /*# MDMCFG0 Channel spacing = 25Khz*/
+                (DEVIATN, 0x15), //5.157471khz
+                //(MCSM1,0x0F),   //CCA always ; default mode RX
+                (MCSM1, 0x00),    //CCA always ; default mode IDLE
+                (MCSM0, 0x18),    //Main Radio Control State Machine Configuration
+                (FOCCFG, 0x1D),   //Frequency Offset Compensation Configuration
+                (BSCFG, 0x1C),    //Bit Synchronization Configuration
+                (AGCCTRL2, 0xC7), //AGC Control
+                (AGCCTRL1, 0x00), //AGC Control
+                (AGCCTRL0, 0xB2), //AGC Control
+                (WORCTRL, 0xFB),  //Wake On Radio Control
+                (FREND1, 0xB6),   //Front End RX Configuration
+                (FSCAL3, 0xE9),   //Frequency Synthesizer Calibration
+                (FSCAL2, 0x2A),   //Frequency Synthesizer Calibration
+                (FSCAL1, 0x00),   //Frequency Synthesizer Calibration
+                (FSCAL0, 0x1F),   //Frequency Synthesizer Calibration
+                (TEST2, 0x81),    //Various Test Settings link to adc retention
+                (TEST1, 0x35),    //Various Test Settings link to adc retention
+                (TEST0, 0x09),    //Various Test Settings link to adc retention
+                (PKTLEN, 38),     //only used by fixed length packets
+            ],
+            patable: [0x60, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            tx: vec![
+                (MDMCFG2, 0x00),  // clear MDMCFG2 to do not send preamble and sync
+                (PKTCTRL0, 0x02), // infinite packet len
+            ],
+            wakeup_ms: 2000,
+            lbt_threshold_dbm: DEFAULT_LBT_THRESHOLD_DBM,
+            sync_search: vec![
+                (MCSM1, 0x0F),   // CCA always ; default mode RX
+                (MDMCFG2, 0x02), // Modem Configuration   2-FSK;  no Manchester ; 16/16 sync word bits detected
+                // configure to receive beginning of sync pattern
+                (SYNC1, 0x55),    // 01010101
+                (SYNC0, 0x50),    // 01010000
+                (MDMCFG4, 0xF6),  // Modem Configuration   RX filter BW = 58Khz
+                (MDMCFG3, 0x83),  // Modem Configuration   26M*((256+83h)*2^6)/2^28 = 2.4kbps
+                (PKTLEN, 1),      // just one byte of synch pattern
+                (PKTCTRL0, 0x00), // fix length, even after a data phase left infinite
+            ],
+            data: vec![
+                (SYNC1, 0xFF),    // 11111111
+                (SYNC0, 0xF0),    // 11110000 la fin du synch pattern et le bit de start
+                (MDMCFG4, 0xF8),  // Modem Configuration   RX filter BW = 58Khz
+                (MDMCFG3, 0x83),  // Modem Configuration   26M*((256+83h)*2^8)/2^28 = 9.59kbps
+                (PKTCTRL0, 0x02), // infinite packet len
+            ],
+        }
+    }
+}
+
+impl RegisterProfile {
+    /// The RADIAN profile at the transmit settings of `radio`, with the SmartRF
+    /// export then the per phase overrides of `config` applied on top.
+    pub fn load(radio: &RadioConfig, config: &RadioProfileConfig) -> Result<Self, ProfileError> {
+        let mut profile = RegisterProfile {
+            wakeup_ms: radio.wakeup_ms,
+            lbt_threshold_dbm: radio.lbt_threshold_dbm,
+            ..Default::default()
+        };
+        // the power level was checked when the configuration was loaded
+        if let Some(pa) = pa_setting(radio.tx_power_dbm) {
+            profile.patable[0] = pa;
+        }
+
+        if let Some(smartrf_file) = &config.smartrf_file {
+            let registers = parse_smartrf(&fs::read_to_string(smartrf_file)?);
+            if registers.is_empty() {
+                return Err(ProfileError::ParseError(format!(
+                    "no register setting found in {}",
+                    smartrf_file.display()
+                )));
+            }
+            profile.import_smartrf(registers);
+        }
+
+        // names were checked when the configuration was loaded
+        for (phase, overrides) in [
+            (&mut profile.base, &config.base),
+            (&mut profile.tx, &config.tx),
+            (&mut profile.sync_search, &config.sync_search),
+            (&mut profile.data, &config.data),
+        ] {
+            for (name, value) in overrides {
+                if let Some(reg_addr) = register_address(name) {
+                    set(phase, reg_addr, *value);
+                }
+            }
+        }
+
+        Ok(profile)
+    }
+
+    // A stock export describes a packet format the RADIAN frames do not follow, only its
+    // analog and RF settings are taken.
+    fn import_smartrf(&mut self, registers: Registers) {
+        for (reg_addr, value) in registers {
+            let name = CONFIG_REGISTER_NAMES[reg_addr as usize];
+            if is_driver_owned(reg_addr) {
+                warn!(
+                    "{} from the SmartRF export ignored, the driver sets it",
+                    name
+                );
+            } else if is_framing(reg_addr) {
+                warn!(
+                    "{} from the SmartRF export ignored, RADIAN framing needs its own",
+                    name
+                );
+            } else {
+                set(&mut self.base, reg_addr, value);
+            }
+        }
+    }
+
+    /// Base values of the registers the other phases change.
+    pub fn restore(&self) -> Registers {
+        let mut restore = Registers::new();
+        for (reg_addr, _) in self.tx.iter().chain(&self.sync_search).chain(&self.data) {
+            if restore.iter().any(|(r, _)| r == reg_addr) {
+                continue;
+            }
+            if let Some(&(_, value)) = self.base.iter().find(|(r, _)| r == reg_addr) {
+                restore.push((*reg_addr, value));
+            }
+        }
+        restore
+    }
+
+    /// Carrier frequency set by FREQ2..0 in the base phase.
+    pub fn frequency_hz(&self) -> u32 {
+        let word = [FREQ2, FREQ1, FREQ0].iter().fold(0u64, |word, &reg_addr| {
+            word << 8 | get(&self.base, reg_addr).unwrap_or(0) as u64
+        });
+        ((word * XOSC_HZ) >> 16) as u32
+    }
+}
+
+/// FREQ2..0 writes tuning the carrier to the synthesizer step closest to `frequency_hz`.
+pub fn frequency_registers(frequency_hz: u32) -> Registers {
+    let word = (((frequency_hz as u64) << 16) + XOSC_HZ / 2) / XOSC_HZ;
+    vec![
+        (FREQ2, (word >> 16) as u8),
+        (FREQ1, (word >> 8) as u8),
+        (FREQ0, word as u8),
+    ]
+}
+
+/// PATABLE setting for `tx_power_dbm`, if it is one of the [`PA_433MHZ`] levels.
+pub fn pa_setting(tx_power_dbm: i8) -> Option<u8> {
+    PA_433MHZ
+        .iter()
+        .find(|(dbm, _)| *dbm == tx_power_dbm)
+        .map(|(_, pa)| *pa)
+}
+
+/// Address of a configuration register from its datasheet name, e.g. `MDMCFG4`.
+pub fn register_address(name: &str) -> Option<u8> {
+    CONFIG_REGISTER_NAMES
+        .iter()
+        .position(|n| n.eq_ignore_ascii_case(name))
+        .map(|reg_addr| reg_addr as u8)
+}
+
+/// GDO pins and FIFO thresholds pace the driver, they cannot be overridden.
+pub fn is_driver_owned(reg_addr: u8) -> bool {
+    matches!(reg_addr, IOCFG2 | IOCFG1 | IOCFG0 | FIFOTHR)
+}
+
+/// Packet format, sync word and modulation registers the RADIAN frames are received with.
+/// They can be overridden per phase but are not taken from a SmartRF export.
+pub fn is_framing(reg_addr: u8) -> bool {
+    matches!(
+        reg_addr,
+        PKTCTRL1 | PKTCTRL0 | PKTLEN | SYNC1 | SYNC0 | MDMCFG2
+    )
+}
+
+pub(crate) fn get(registers: &[(u8, u8)], reg_addr: u8) -> Option<u8> {
+    registers
+        .iter()
+        .find(|(r, _)| *r == reg_addr)
+        .map(|(_, value)| *value)
+}
+
+pub(crate) fn set(registers: &mut Registers, reg_addr: u8, value: u8) {
+    match registers.iter_mut().find(|(r, _)| *r == reg_addr) {
+        Some(register) => register.1 = value,
+        None => registers.push((reg_addr, value)),
+    }
+}
+
+/// Register settings of a SmartRF Studio export, whatever its template: each line holding a
+/// register name (optionally prefixed as in `SMARTRF_SETTING_MDMCFG4`) and a `0xNN` value,
+/// after the name or, as in the `rfSettings` template (`0xC7,  // AGCCTRL2`), before it.
+/// Other lines are ignored.
+pub fn parse_smartrf(export: &str) -> Registers {
+    let mut registers = Registers::new();
+    for line in export.lines() {
+        let tokens: Vec<&str> = line
+            .split(|c: char| c.is_whitespace() || ",;(){}=".contains(c))
+            .filter(|token| !token.is_empty())
+            .collect();
+        let Some((position, reg_addr)) = tokens.iter().enumerate().find_map(|(i, token)| {
+            let name = token
+                .trim_start_matches("SMARTRF_SETTING_")
+                .trim_start_matches("CC1101_");
+            Some((i, register_address(name)?))
+        }) else {
+            continue;
+        };
+        let value = tokens[position + 1..]
+            .iter()
+            .find_map(|token| hex_value(token))
+            .or_else(|| tokens[..position].iter().find_map(|token| hex_value(token)));
+        if let Some(value) = value {
+            set(&mut registers, reg_addr, value);
+        }
+    }
+    registers
+}
+
+// A 4 digit hex token is the register address, some templates print it
+fn hex_value(token: &str) -> Option<u8> {
+    let digits = token.strip_prefix("0x").or(token.strip_prefix("0X"))?;
+    if digits.len() > 2 {
+        return None;
+    }
+    u8::from_str_radix(digits, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn parse_smartrf_works() {
+        let header = r#"
+            // Data rate = 2.39897
+            #define SMARTRF_SETTING_IOCFG0    0x06
+            #define SMARTRF_SETTING_MDMCFG4   0xF7
+            #define SMARTRF_SETTING_AGCCTRL2  0x43
+        "#;
+        assert_eq!(
+            parse_smartrf(header),
+            vec![(IOCFG0, 0x06), (MDMCFG4, 0xF7), (AGCCTRL2, 0x43)]
+        );
+
+        let table = "MDMCFG4  0x0010  0xF5  Modem Configuration\nPA_TABLE0 0xC0\n";
+        assert_eq!(parse_smartrf(table), vec![(MDMCFG4, 0xF5)]);
+
+        let rf_settings = "    0xC7,  // AGCCTRL2        AGC Control\n{CC1101_FREND1, 0x56},";
+        assert_eq!(
+            parse_smartrf(rf_settings),
+            vec![(AGCCTRL2, 0xC7), (FREND1, 0x56)]
+        );
+    }
+
+    #[test]
+    fn import_smartrf_keeps_framing() {
+        let export = "PKTCTRL0 0x05\nSYNC1 0xD3\nMDMCFG2 0x13\nIOCFG0 0x06\nAGCCTRL2 0x43\n";
+        let mut profile = RegisterProfile::default();
+        profile.import_smartrf(parse_smartrf(export));
+
+        let default = RegisterProfile::default();
+        for reg_addr in [PKTCTRL0, SYNC1, MDMCFG2, IOCFG0] {
+            assert_eq!(get(&profile.base, reg_addr), get(&default.base, reg_addr));
+        }
+        assert_eq!(get(&profile.base, AGCCTRL2), Some(0x43));
+    }
+
+    #[test]
+    fn load_applies_overrides() {
+        let config = RadioProfileConfig {
+            base: BTreeMap::from([("AGCCTRL2".to_string(), 0x43)]),
+            data: BTreeMap::from([("mdmcfg4".to_string(), 0xF7)]),
+            ..Default::default()
+        };
+        let radio = RadioConfig {
+            tx_power_dbm: 10,
+            ..Default::default()
+        };
+        let profile = RegisterProfile::load(&radio, &config).unwrap();
+        assert_eq!(profile.patable[0], 0xC0);
+        assert!(profile.base.contains(&(AGCCTRL2, 0x43)));
+        assert!(profile.data.contains(&(MDMCFG4, 0xF7)));
+        assert_eq!(profile.base.len(), RegisterProfile::default().base.len());
+    }
+
+    #[test]
+    fn frequency_works() {
+        let profile = RegisterProfile::default();
+        assert_eq!(profile.frequency_hz(), 433_819_854);
+        assert_eq!(
+            frequency_registers(profile.frequency_hz()),
+            vec![(FREQ2, 0x10), (FREQ1, 0xAF), (FREQ0, 0x75)]
+        );
+        assert_eq!(
+            frequency_registers(433_820_000),
+            vec![(FREQ2, 0x10), (FREQ1, 0xAF), (FREQ0, 0x75)]
+        );
+    }
+
+    #[test]
+    fn restore_works() {
+        let restore = RegisterProfile::default().restore();
+        assert!(restore.contains(&(MDMCFG2, 0x02)));
+        assert!(restore.contains(&(MCSM1, 0x00)));
+        assert!(restore.contains(&(PKTLEN, 38)));
+        assert_eq!(
+            restore.iter().filter(|(r, _)| *r == MDMCFG4).count(),
+            1,
+            "restored once"
+        );
+    }
+}
@@ -1,84 +1,294 @@
-use std::process::exit;
+//! RADIAN protocol: requests, start/stop bit encoding, oversampled frame decoding and CRC.
+//!
+//! This module only needs `core`. Functions returning a `Vec` also need the `alloc` feature,
+//! each of them has an `_into` counterpart writing to a caller provided buffer.
 
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::fmt;
 use crc::Crc;
 use log::{debug, trace};
 
+const CRC_KERMIT: Crc<u16> = Crc::<u16>::new(&crc::CRC_16_KERMIT);
+
+pub const SYNCH_PATTERN: [u8; 9] = [0x50, 0x00, 0x00, 0x00, 0x03, 0xFF, 0xFF, 0xFF, 0xFF];
+
+const SAMPLES_PER_BIT: usize = 4;
+
+/// Frame decoded from a 4x oversampled receive buffer, with the decoder statistics.
+#[cfg(feature = "alloc")]
+#[derive(Debug, Default, PartialEq)]
+pub struct DecodedFrame {
+    pub bytes: Vec<u8>,
+    pub confidence: Vec<f32>, // per byte, 1.0 when all samples of every bit agreed
+    pub corrected_glitches: u32, // bits whose samples disagreed
+    pub framing_errors: u32,  // bad stop or start bits, decoding resynchronized after them
+    pub truncated: bool,      // the last byte was cut by the end of the buffer
+}
+
+#[cfg(feature = "alloc")]
+impl DecodedFrame {
+    pub fn min_confidence(&self) -> f32 {
+        self.confidence.iter().copied().fold(1.0, f32::min)
+    }
+
+    /// The bytes received in full, without a last one cut by the end of the buffer.
+    pub fn complete_bytes(&self) -> &[u8] {
+        &self.bytes[..self.bytes.len() - self.truncated as usize]
+    }
+}
+
+/// Decoder statistics of `decode_oversampled_into`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecodeStats {
+    pub len: usize, // bytes written to the output buffer
+    pub min_confidence: f32,
+    pub corrected_glitches: u32,
+    pub framing_errors: u32,
+    pub truncated: bool, // the last byte was cut by the end of the buffer
+}
+
+impl Default for DecodeStats {
+    fn default() -> Self {
+        Self {
+            len: 0,
+            min_confidence: 1.0,
+            corrected_glitches: 0,
+            framing_errors: 0,
+            truncated: false,
+        }
+    }
+}
+
+struct Samples<'a>(&'a [u8]);
+
+impl Samples<'_> {
+    fn len(&self) -> usize {
+        self.0.len() * 8
+    }
+
+    fn get(&self, i: usize) -> u8 {
+        (self.0[i / 8] >> (7 - i % 8)) & 1
+    }
+
+    // Majority vote over the samples of the bit starting at `pos`, a tie is settled by the
+    // sample at the center of the bit. Confidence is 1.0 for 4-0, 0.5 for 3-1 and 0 for 2-2.
+    fn bit(&self, pos: usize) -> (u8, f32) {
+        let ones: usize = (pos..pos + SAMPLES_PER_BIT)
+            .map(|i| self.get(i) as usize)
+            .sum();
+        let bit = match (ones * 2).cmp(&SAMPLES_PER_BIT) {
+            core::cmp::Ordering::Greater => 1,
+            core::cmp::Ordering::Less => 0,
+            core::cmp::Ordering::Equal => self.get(pos + SAMPLES_PER_BIT / 2),
+        };
+        let confidence = (ones * 2).abs_diff(SAMPLES_PER_BIT) as f32 / SAMPLES_PER_BIT as f32;
+        (bit, confidence)
+    }
+
+    fn is_falling_edge(&self, t: usize) -> bool {
+        t > 0
+            && t + 1 < self.len()
+            && self.get(t - 1) == 1
+            && self.get(t) == 0
+            && self.get(t + 1) == 0
+    }
+
+    // Start bit edge nearest to where it is expected, tolerating a drift of half a bit. Without
+    // an edge, as after a bad stop bit, a low level at the expected position is taken.
+    fn find_start_bit(&self, expected: usize) -> Option<usize> {
+        let tolerance = SAMPLES_PER_BIT / 2;
+        (0..=tolerance)
+            .flat_map(|d| [expected + d, expected.wrapping_sub(d)])
+            .find(|&t| t + SAMPLES_PER_BIT <= self.len() && self.is_falling_edge(t))
+            .or_else(|| {
+                (expected + SAMPLES_PER_BIT <= self.len() && self.bit(expected).0 == 0)
+                    .then_some(expected)
+            })
+    }
+
+    // Next start bit preceded by two full stop bits, to resynchronize after an error
+    fn resync(&self, from: usize) -> Option<usize> {
+        let idle = 2 * SAMPLES_PER_BIT;
+        (from.max(idle)..self.len())
+            .find(|&t| self.is_falling_edge(t) && (t - idle..t).all(|i| self.get(i) == 1))
+    }
+}
+
 // Remove the start- and stop-bits in the bitstream, also decode oversampled bit 0xF0 => 1,0
 // 01234567 ###01234 567###01 234567## #0123456 (# -> Start/Stop bit)
 // is decoded to:
 // 76543210 76543210 76543210 76543210
+// The synch word consumes the first start bit, so the buffer starts with the first data bit.
+// Decoding stops when the buffer is exhausted, the line stays idle or goes into a break, or
+// when `emit` returns false. A byte cut by the end of the buffer is emitted as a shift register
+// holds it, the bits received in the high bits, with a zero confidence.
+fn decode(rx_buffer: &[u8], mut emit: impl FnMut(u8, f32) -> bool) -> DecodeStats {
+    let samples = Samples(rx_buffer);
+    let mut stats = DecodeStats::default();
+    let mut pos = 0;
+
+    while pos + SAMPLES_PER_BIT <= samples.len() {
+        let data_bits = ((samples.len() - pos) / SAMPLES_PER_BIT).min(8);
+        let mut byte = 0u8;
+        let mut confidence = 1.0f32;
+        let mut glitches = 0;
+        for i in 0..data_bits {
+            let (bit, c) = samples.bit(pos + i * SAMPLES_PER_BIT);
+            byte |= bit << (i + 8 - data_bits);
+            confidence = confidence.min(c);
+            glitches += (c < 1.0) as u32;
+        }
+        if data_bits < 8 {
+            // a low line cut short is the start of a break, not a byte
+            if byte == 0 && confidence == 1.0 {
+                break;
+            }
+            trace!(
+                "dec[{}]={:02X} cut after {} bits",
+                stats.len,
+                byte,
+                data_bits
+            );
+            if emit(byte, 0.0) {
+                stats.len += 1;
+                stats.min_confidence = 0.0;
+                stats.corrected_glitches += glitches;
+                stats.truncated = true;
+            }
+            break;
+        }
+
+        // stop bits, the last ones may be cut by the end of the buffer
+        let stop = pos + 8 * SAMPLES_PER_BIT;
+        let mut framing_ok = true;
+        for i in 0..2 {
+            let bit_pos = stop + i * SAMPLES_PER_BIT;
+            if bit_pos + SAMPLES_PER_BIT > samples.len() {
+                break;
+            }
+            let (bit, c) = samples.bit(bit_pos);
+            framing_ok &= bit == 1;
+            confidence = confidence.min(c);
+            glitches += (c < 1.0) as u32;
+        }
+
+        if !framing_ok && byte == 0 && confidence == 1.0 {
+            trace!("break after {} bytes", stats.len);
+            break;
+        }
+        trace!("dec[{}]={:02X} {}", stats.len, byte, confidence);
+        if !emit(byte, confidence) {
+            break;
+        }
+        stats.len += 1;
+        stats.min_confidence = stats.min_confidence.min(confidence);
+        stats.corrected_glitches += glitches;
+
+        // keep the timing through a bad stop bit if the start bit is where expected
+        let start = match samples.find_start_bit(stop + 2 * SAMPLES_PER_BIT) {
+            Some(t) if framing_ok => t,
+            start => {
+                // no more start bit after a good byte is the end of the transmission
+                let Some(t) = start.or_else(|| samples.resync(stop)) else {
+                    break;
+                };
+                debug!("framing error after byte {}", stats.len - 1);
+                stats.framing_errors += 1;
+                t
+            }
+        };
+        pos = start + SAMPLES_PER_BIT;
+    }
+
+    stats
+}
+
+#[cfg(feature = "alloc")]
+pub fn decode_oversampled(rx_buffer: &[u8]) -> DecodedFrame {
+    let mut bytes = Vec::new();
+    let mut confidence = Vec::new();
+    let stats = decode(rx_buffer, |byte, c| {
+        bytes.push(byte);
+        confidence.push(c);
+        true
+    });
+
+    DecodedFrame {
+        bytes,
+        confidence,
+        corrected_glitches: stats.corrected_glitches,
+        framing_errors: stats.framing_errors,
+        truncated: stats.truncated,
+    }
+}
+
+/// Decode into `output`, stopping when it is full.
+pub fn decode_oversampled_into(rx_buffer: &[u8], output: &mut [u8]) -> DecodeStats {
+    let mut len = 0;
+    decode(rx_buffer, |byte, _| {
+        let Some(slot) = output.get_mut(len) else {
+            return false;
+        };
+        *slot = byte;
+        len += 1;
+        true
+    })
+}
+
+#[cfg(feature = "alloc")]
 pub fn decode_4bitpbit_serial(rx_buffer: &[u8], l_total_byte: u16) -> Vec<u8> {
-    let mut decoded = [0u8; 200];
-    let mut bit_cnt: u8 = 0;
-    let mut bit_cnt_flush_s8: i8 = 0;
-    let mut bit_pol: u8;
-    let mut dest_bit_cnt: u8 = 0;
-    let mut dest_byte_cnt: usize = 0;
-    let mut current_rx_byte: u8;
-
-    // set 1st bit polarity
-    bit_pol = rx_buffer[0] & 0x80; // initialize with 1st bit state
-
-    for i in 0..l_total_byte {
-        current_rx_byte = rx_buffer[i as usize];
-        trace!("{}", current_rx_byte);
-
-        for _j in 0..8 {
-            if (current_rx_byte & 0x80) == bit_pol {
-                bit_cnt += 1;
-            } else if bit_cnt == 1 {
-                // previous bit was a glitch so bit has not really changed
-                bit_pol = current_rx_byte & 0x80; // restore correct bit polarity
-                bit_cnt = (bit_cnt_flush_s8 + 1) as u8; // hope that previous bit was correctly decoded
-            } else {
-                // bit polarity has changed
-                bit_cnt_flush_s8 = bit_cnt as i8;
-                bit_cnt = (bit_cnt + 2) / 4;
-                bit_cnt_flush_s8 -= bit_cnt as i8 * 4;
-
-                for _k in 0..bit_cnt {
-                    // insert the number of decoded bit
-                    if dest_bit_cnt < 8 {
-                        // if data byte
-                        decoded[dest_byte_cnt] >>= 1;
-                        decoded[dest_byte_cnt] |= bit_pol;
-                    }
-                    dest_bit_cnt += 1;
-
-                    if dest_bit_cnt == 10 && bit_pol == 0 {
-                        debug!("stop bit error10");
-                        exit(1); // TODO better error handling
-                    }
-                    if dest_bit_cnt >= 11 && bit_pol == 0 {
-                        // start bit
-                        dest_bit_cnt = 0;
-                        debug!("dec[{}]={} {}", i, dest_byte_cnt, decoded[dest_byte_cnt]);
-                        dest_byte_cnt += 1;
-                    }
-                }
-                bit_pol = current_rx_byte & 0x80;
-                bit_cnt = 1;
+    let l_total_byte = (l_total_byte as usize).min(rx_buffer.len());
+    decode_oversampled(&rx_buffer[..l_total_byte]).bytes
+}
+
+/// Bytes the way the CC1101 receives them after the synch word: start bit, 8 bits LSB first
+/// and 2 stop bits, 4 samples per bit, into a zeroed `samples`. The synch word consumes the first
+/// start bit. Returns the number of sample bytes, a trailing low level included.
+#[cfg(test)]
+pub(crate) fn oversample_into(frame: &[u8], samples: &mut [u8]) -> usize {
+    let mut pos = 0;
+    for (i, &byte) in frame.iter().enumerate() {
+        let start_bit = (i > 0).then_some(0);
+        let data_bits = (0..8).map(|bit| (byte >> bit) & 1);
+        for level in start_bit.into_iter().chain(data_bits).chain([1, 1]) {
+            for _ in 0..SAMPLES_PER_BIT {
+                samples[pos / 8] |= level << (7 - pos % 8);
+                pos += 1;
             }
-            current_rx_byte <<= 1;
-        } // scan TX_bit
-    } // scan TX_byte
-    decoded[0..dest_byte_cnt + 1].to_vec() // TODO not sure about that but the rest is 0 so ...
+        }
+    }
+    // a trailing low level lets the decoder flush the last stop bits
+    (pos + SAMPLES_PER_BIT).div_ceil(8)
 }
 
-/**
- * Reverses the bit order of the input data and adds a start bit before and a stop bit
- * after each byte.
- *
- * @param input_buffer Points to the unencoded data.
- * @param input_buffer Number of bytes of unencoded data.
- * @param output_buffer Points to the encoded data.
- */
-pub fn encode2serial_1_3(input: [u8; 19]) -> [u8; 30] {
+/// Receive buffer length for a frame of `size_byte` bytes, 11 bits per byte 4 times oversampled.
+/// A sixteenth more is read: the meter clock runs a few percent slow, 45 samples per byte on
+/// the captures instead of 44, and a tight window cuts off the CRC.
+pub const fn rx_window_len(size_byte: usize) -> usize {
+    let bits = size_byte * (8 + 3);
+    (bits / 8 + 1 + bits / 8 / 16) * SAMPLES_PER_BIT
+}
+
+/// Length of `input_len` bytes once encoded by `encode2serial_1_3`.
+pub const fn encoded_len(input_len: usize) -> usize {
+    (input_len * 12).saturating_sub(3).div_ceil(8) + 1
+}
+
+/// Reverse the bit order of each byte and frame it with a start bit and three stop bits.
+#[cfg(feature = "alloc")]
+pub fn encode2serial_1_3(input: &[u8]) -> Vec<u8> {
+    let mut output = vec![0u8; encoded_len(input.len())];
+    encode2serial_1_3_into(input, &mut output);
+    output
+}
+
+/// Encode into `output`, which must hold `encoded_len(input.len())` bytes. Returns that length.
+pub fn encode2serial_1_3_into(input: &[u8], output: &mut [u8]) -> usize {
     // Adds a start and stop bit and reverses the bit order.
     // 76543210 76543210 76543210 76543210
     // is encoded to:
     // #0123456 7###0123 4567###0 1234567# ##012345 6s7# (# -> Start/Stop bit)
-    let mut output: [u8; 30] = [0; 30];
     let mut j = 0;
 
     for i in 0..(input.len() * 8) {
@@ -128,54 +338,274 @@ pub fn encode2serial_1_3(input: [u8; 19]) -> [u8; 30] {
     let final_bytepos = j / 8;
     output[final_bytepos] = 0xFF;
 
+    final_bytepos + 1
+}
+
+/**
+ * Inverse of encode2serial_1_3: strips the start and stop bits and restores the bit order.
+ * Decoding stops at the first missing start bit, i.e. on the trailing 0xFF padding.
+ */
+#[cfg(feature = "alloc")]
+pub fn decode_serial_1_3(input: &[u8]) -> Vec<u8> {
+    let bit = |j: usize| (input[j / 8] >> (7 - (j % 8))) & 1;
+    let mut output = Vec::new();
+    let mut j = 0;
+
+    // start bit + 8 data bits + 3 stop bits
+    while j + 9 <= input.len() * 8 && bit(j) == 0 {
+        let mut byte = 0u8;
+        for i in 0..8 {
+            byte |= bit(j + 1 + i) << i;
+        }
+        output.push(byte);
+        j += 12;
+    }
+
     output
 }
 
-pub fn make_radian_master_req(year: u8, serial: u32) -> [u8; 39] {
-    let mut to_encode: [u8; 19] = [
-        0x13, 0x10, 0x00, 0x45, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x45, 0x20, 0x0A, 0x50, 0x14, 0x00,
-        0x0A, 0x40, 0xFF, 0xFF,
-    ];
+/// CRC of a RADIAN frame, computed over everything but the two trailing CRC bytes.
+pub fn frame_crc(frame: &[u8]) -> u16 {
+    CRC_KERMIT.checksum(&frame[..frame.len().saturating_sub(2)])
+}
 
-    let synch_pattern: [u8; 9] = [0x50, 0x00, 0x00, 0x00, 0x03, 0xFF, 0xFF, 0xFF, 0xFF];
+/// Check the little-endian CRC stored at the end of a RADIAN frame.
+pub fn check_crc(frame: &[u8]) -> bool {
+    frame.len() > 2
+        && frame_crc(frame) == u16::from_le_bytes([frame[frame.len() - 2], frame[frame.len() - 1]])
+}
+
+pub const CONTROL_REQUEST: u8 = 0x10;
+pub const CONTROL_RESPONSE: u8 = 0x11;
 
-    // Set year and serial number
-    to_encode[4] = year;
-    to_encode[5] = ((serial & 0x00FF0000) >> 16) as u8;
-    to_encode[6] = ((serial & 0x0000FF00) >> 8) as u8;
-    to_encode[7] = (serial & 0x000000FF) as u8;
+/// Command reading the basic data set: index, battery, wake hours and index history.
+pub const COMMAND_READ_DATA: [u8; 3] = [0x00, 0x0A, 0x40];
 
-    // Calculate CRC
-    let crc = Crc::<u16>::new(&crc::CRC_16_KERMIT).checksum(&to_encode[0..to_encode.len() - 2]);
-    to_encode[to_encode.len() - 1] = ((crc & 0xFF00) >> 8) as u8;
-    to_encode[to_encode.len() - 2] = (crc & 0x00FF) as u8;
+/// Address of a RADIAN device: a 2 byte prefix, then a year and a 3 byte serial.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Address {
+    pub prefix: [u8; 2],
+    pub year: u8,
+    pub serial: u32,
+}
 
-    // Encode to serial
-    let encoded = encode2serial_1_3(to_encode);
+impl Address {
+    /// Address this reader sends its requests from.
+    pub const READER: Address = Address {
+        prefix: [0x00, 0x45],
+        year: 0x20,
+        serial: 0x0A5014,
+    };
 
-    let mut result: [u8; 39] = [0; 39];
-    result[..9].copy_from_slice(&synch_pattern);
-    result[9..].copy_from_slice(&encoded);
-    result
+    pub fn meter(year: u8, serial: u32) -> Self {
+        Self {
+            prefix: [0x00, 0x45],
+            year,
+            serial,
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            prefix: [bytes[0], bytes[1]],
+            year: bytes[2],
+            serial: u32::from_be_bytes([0, bytes[3], bytes[4], bytes[5]]),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; 6] {
+        let serial = self.serial.to_be_bytes();
+        [
+            self.prefix[0],
+            self.prefix[1],
+            self.year,
+            serial[1],
+            serial[2],
+            serial[3],
+        ]
+    }
+}
+
+/// Longest command a request carries, its frame length must fit the 1 byte length field.
+pub const MAX_COMMAND_LEN: usize = u8::MAX as usize - 16;
+
+/// Request sent to a meter: length, control, destination and source addresses, command, CRC.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RadianRequest<'a> {
+    pub control: u8,
+    pub destination: Address,
+    pub source: Address,
+    pub command: &'a [u8],
+}
+
+impl<'a> RadianRequest<'a> {
+    pub fn new(destination: Address, command: &'a [u8]) -> Self {
+        Self {
+            control: CONTROL_REQUEST,
+            destination,
+            source: Address::READER,
+            command,
+        }
+    }
+
+    pub fn frame_len(&self) -> usize {
+        16 + self.command.len()
+    }
+
+    /// Length of the synch pattern and the encoded frame.
+    pub fn encoded_len(&self) -> usize {
+        SYNCH_PATTERN.len() + encoded_len(self.frame_len())
+    }
+
+    /// Frame with its length and CRC, before start/stop bit encoding.
+    ///
+    /// Panics if the command is longer than `MAX_COMMAND_LEN`.
+    #[cfg(feature = "alloc")]
+    pub fn to_frame(&self) -> Vec<u8> {
+        let mut frame = vec![0u8; self.frame_len()];
+        self.to_frame_into(&mut frame)
+            .expect("command longer than MAX_COMMAND_LEN");
+        frame
+    }
+
+    /// Write the frame to `output` and return its length, `frame_len()`. None if the command is
+    /// longer than `MAX_COMMAND_LEN` or `output` is too short.
+    pub fn to_frame_into(&self, output: &mut [u8]) -> Option<usize> {
+        if self.command.len() > MAX_COMMAND_LEN {
+            return None;
+        }
+        let len = self.frame_len();
+        let frame = output.get_mut(..len)?;
+        frame[0] = len as u8;
+        frame[1] = self.control;
+        frame[2..8].copy_from_slice(&self.destination.to_bytes());
+        frame[8..14].copy_from_slice(&self.source.to_bytes());
+        frame[14..len - 2].copy_from_slice(self.command);
+
+        let crc = frame_crc(frame);
+        frame[len - 2..].copy_from_slice(&crc.to_le_bytes());
+        Some(len)
+    }
+
+    /// Synch pattern followed by the start/stop bit encoded frame, ready to transmit.
+    ///
+    /// Panics if the command is longer than `MAX_COMMAND_LEN`.
+    #[cfg(feature = "alloc")]
+    pub fn encode(&self) -> Vec<u8> {
+        let mut result = vec![0u8; self.encoded_len()];
+        self.encode_into(&mut result)
+            .expect("command longer than MAX_COMMAND_LEN");
+        result
+    }
+
+    /// Write the encoded request to `output` and return its length, `encoded_len()`. None if the
+    /// command is longer than `MAX_COMMAND_LEN` or `output` is too short.
+    pub fn encode_into(&self, output: &mut [u8]) -> Option<usize> {
+        let mut frame = [0u8; u8::MAX as usize];
+        let frame_len = self.to_frame_into(&mut frame)?;
+        if output.len() < self.encoded_len() {
+            return None;
+        }
+
+        output[..SYNCH_PATTERN.len()].copy_from_slice(&SYNCH_PATTERN);
+        Some(
+            SYNCH_PATTERN.len()
+                + encode2serial_1_3_into(&frame[..frame_len], &mut output[SYNCH_PATTERN.len()..]),
+        )
+    }
+}
+
+#[cfg(feature = "alloc")]
+pub fn make_radian_master_req(year: u8, serial: u32) -> Vec<u8> {
+    RadianRequest::new(Address::meter(year, serial), &COMMAND_READ_DATA).encode()
+}
+
+/// Readings of a meter data frame.
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "std", derive(serde::Serialize))]
+pub struct MeterData {
+    /// Index in liters after the offset and pulse weight are applied
+    pub liters: i64,
+    /// Index as stored in the RADIAN register
+    pub raw_liters: i32,
+    /// How many times the meter has been read
+    pub reads_counter: i32,
+    /// Battery life left, in months
+    pub battery_left: i32,
+    /// Hour the meter starts listening, e.g. 8 for 8am
+    pub time_start: i32,
+    /// Hour the meter stops listening, e.g. 16 for 4pm
+    pub time_end: i32,
+}
+
+/// Decoded meter report too short to carry the index.
+#[derive(Debug, PartialEq)]
+pub struct ReportTooShort {
+    pub size: usize,
+}
+
+impl fmt::Display for ReportTooShort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Failed to read meter data, decode_buffer size {} is < 30",
+            self.size
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ReportTooShort {}
+
+pub fn parse_meter_report(decoded_buffer: &[u8]) -> Result<MeterData, ReportTooShort> {
+    let size = decoded_buffer.len();
+    let mut data = MeterData::default();
+
+    if size < 30 {
+        return Err(ReportTooShort { size });
+    }
+    if size >= 30 {
+        let b18 = decoded_buffer[18] as i32;
+        let b19 = decoded_buffer[19] as i32;
+        let b20 = decoded_buffer[20] as i32;
+        let b21 = decoded_buffer[21] as i32;
+        data.raw_liters = b18 + b19 * 256 + b20 * 65536 + b21 * 16777216;
+        data.liters = data.raw_liters as i64;
+    }
+    if size > 48 {
+        data.reads_counter = decoded_buffer[48] as i32;
+        data.battery_left = decoded_buffer[31] as i32;
+        data.time_start = decoded_buffer[44] as i32;
+        data.time_end = decoded_buffer[45] as i32;
+    }
+
+    Ok(data)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use hex;
+    #[cfg(feature = "std")]
+    use crate::emulator::oversample;
+
+    // Request for meter 16-1087372, before and after start/stop bit encoding
+    const REQUEST_FRAME: [u8; 19] = [
+        0x13, 0x10, 0x00, 0x45, 0x10, 0x10, 0x97, 0x8c, 0x00, 0x45, 0x20, 0x0a, 0x50, 0x14, 0x00,
+        0x0a, 0x40, 0xf1, 0xe3,
+    ];
+    const REQUEST_ENCODED: [u8; 30] = [
+        0x64, 0x70, 0x47, 0x00, 0x75, 0x17, 0x04, 0x70, 0x47, 0x74, 0xf1, 0x8f, 0x00, 0x75, 0x17,
+        0x02, 0x72, 0x87, 0x05, 0x71, 0x47, 0x00, 0x72, 0x87, 0x01, 0x74, 0x7f, 0x63, 0xff, 0xff,
+    ];
 
     #[test]
+    #[cfg(feature = "std")]
     fn encode_works() {
-        let input_buffer: [u8; 19] = [
-            0x13, 0x10, 0x00, 0x45, 0x10, 0x10, 0x97, 0x8c, 0x00, 0x45, 0x20, 0x0a, 0x50, 0x14,
-            0x00, 0x0a, 0x40, 0xf1, 0xe3,
-        ];
-        let encoded_buffer_hex = "64704700751704704774f18f00751702728705714700728701747f63ffff";
-        let encoded_buffer = encode2serial_1_3(input_buffer);
-        assert_eq!(hex::encode(encoded_buffer), encoded_buffer_hex);
+        assert_eq!(encode2serial_1_3(&REQUEST_FRAME), REQUEST_ENCODED);
     }
 
     #[test]
+    #[cfg(feature = "std")]
     fn decode_works() {
         let encoded_buffer_hex = "00fffff0ff8780078007fc000000003fe1e1e001e1ff000000f00ff8078780007fc00001e3c1fe001e0f000ff0000000007fc3c7c007c3fe01ffe0001ff0ffff0000ff800787807ffc0003ffffffe000000001ff0f0000000ff87fff80007fc000000003fe00001e1e1ff0f0f00fffff8000078007fc000000003fe0000001e0ff00ff800007f87c7c7c003fe3e0000001ff00f0ff000ff87f8000007fc00003c003fe1e1fe0001ff0ff000f00ff807ff87807fc00003fc03fe01e01fe01ff00f00ff00ff800007f807fc3fc03fc03fe00001fe01ff00f0000f0ff8078000787fc003c3fc03fe1e001fe01ff0000000007f8000000003fc01fe00001ff00e00f000ff8007800007fc3c0000003fe1e00001e1ff000f00000ff8000000007fc000000003fe000000001ff000000000ff8000000007fc000000003fe00000001fff00000000fff800000003ffe00000001fff00000000fff800000007ffc00000003ffe00000001fff00000000fff800000007ffc00000003ffe00000001fff00000000fff800000007ffc0003fe3fffe1f1e0001fff0ffff80007f8000000003fe003fe1fffff000fff00fff87fff80007fc000000003fe01e01e001ff0f000ff0fff87fff80007fc000000003fe01ffffe01ff000ff00ffff87fff80007fc000000003fe01fe01e00ff0fff007ffff87fffc0003fe000000001ff0f1fffff0ff8078000007fc00003c003fe000000001ff0f0f000ffff87ffff8007fc00003c003fe000000001ff0f0f000ffff8007800787fc00003e003fe000000001ff0f8ff8ff8ffc07ffc3fc3fc00001e001ff000000000ff87f87ffffffc3c3c0003ffe00001e001ff000000000ff87f8078787fc3c0003c3ffe00001e001ff000000000ff8078787ffffc03c3ffc3ffe00001f001ff0000000007f87c787f807fc3fc03e3fffe00001f000ff8000000007fc03fffc00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000";
         let decoded_buffer_hex = "7c110045200a501400450e0f94f800010f0050e51000400615011a03100d232e3032323033304242343100000612040141040000000000008080808080808080808080808080d8850f00ec9c0f0012b10f003ecc0f0026e70f007d021000c51f1000c54410006d6e1000fb85100053a11000eaba100035d31000f0";
@@ -185,10 +615,191 @@ mod tests {
     }
 
     #[test]
+    fn parse_meter_report_lengths() {
+        // a noisy reception can decode to any length, up to the reads counter excluded
+        let mut report = [0u8; 49];
+        report[18..22].copy_from_slice(&1107280i32.to_le_bytes());
+        report[48] = 65;
+        assert_eq!(
+            parse_meter_report(&report[..29]),
+            Err(ReportTooShort { size: 29 })
+        );
+        for size in [30, 48] {
+            let data = parse_meter_report(&report[..size]).unwrap();
+            assert_eq!(data.raw_liters, 1107280);
+            assert_eq!(data.reads_counter, 0);
+        }
+        assert_eq!(parse_meter_report(&report).unwrap().reads_counter, 65);
+    }
+
+    #[test]
+    fn rx_window_holds_slow_frames() {
+        // the data frame at 45 samples per byte, as on the captures, CRC included
+        assert!(rx_window_len(0x7C) * 8 >= 0x7C * 45);
+        assert!(rx_window_len(0x12) * 8 >= 0x12 * 45);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn decode_oversampled_corrects_glitches() {
+        let frame = make_radian_master_req(16, 1087372);
+        let mut samples = oversample(&frame);
+        // one wrong sample in the first data bit of bytes 0, 5 and 10
+        for byte in [0, 5, 10] {
+            let sample = byte * 44 + 1;
+            samples[sample / 8] ^= 0x80 >> (sample % 8);
+        }
+
+        let decoded = decode_oversampled(&samples);
+        assert_eq!(decoded.bytes, frame);
+        assert_eq!(decoded.corrected_glitches, 3);
+        assert_eq!(decoded.framing_errors, 0);
+        assert_eq!(decoded.confidence[5], 0.5);
+        assert_eq!(decoded.confidence[6], 1.0);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn decode_oversampled_resynchronizes() {
+        let frame = make_radian_master_req(16, 1087372);
+
+        // bad stop bits of byte 10 (samples 472 to 480), the start bit of byte 11 is still in place
+        let mut samples = oversample(&frame);
+        samples[59] = 0x00;
+        let decoded = decode_oversampled(&samples);
+        assert_eq!(decoded.framing_errors, 1);
+        assert_eq!(decoded.bytes, frame);
+
+        // burst of noise over bytes 10 and 11, decoding resumes on the start bit of byte 12
+        let mut samples = oversample(&frame);
+        samples[55..65].fill(0x3C);
+        let decoded = decode_oversampled(&samples);
+        assert_eq!(decoded.framing_errors, 1);
+        assert_eq!(decoded.bytes.len(), frame.len());
+        assert_eq!(decoded.bytes[..10], frame[..10]);
+        assert_eq!(decoded.bytes[12..], frame[12..]);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn decode_serial_1_3_works() {
+        let encoded_buffer =
+            hex::decode("64704700751704704774f18f00751702728705714700728701747f63ffff").unwrap();
+        assert_eq!(
+            hex::encode(decode_serial_1_3(&encoded_buffer)),
+            "131000451010978c0045200a5014000a40f1e3"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn check_crc_works() {
+        let mut frame = hex::decode("131000451010978c0045200a5014000a40f1e3").unwrap();
+        assert!(check_crc(&frame));
+        frame[7] ^= 0x01;
+        assert!(!check_crc(&frame));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
     fn make_radian_master_req_works() {
         assert_eq!(
             hex::encode(make_radian_master_req(16, 1087372)),
             "5000000003ffffffff64704700751704704774f18f00751702728705714700728701747f63ffff"
         );
     }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn radian_request_works() {
+        let request = RadianRequest::new(Address::meter(16, 1087372), &COMMAND_READ_DATA);
+        assert_eq!(
+            hex::encode(request.to_frame()),
+            "131000451010978c0045200a5014000a40f1e3"
+        );
+
+        // any command length survives encoding
+        let request = RadianRequest::new(Address::meter(14, 1021176), &[0x00, 0x0A, 0x41, 0x02]);
+        let frame = request.to_frame();
+        assert_eq!(frame[0] as usize, frame.len());
+        assert!(check_crc(&frame));
+        let encoded = request.encode();
+        assert_eq!(decode_serial_1_3(&encoded[SYNCH_PATTERN.len()..]), frame);
+        assert_eq!(Address::from_bytes(&frame[2..8]), request.destination);
+    }
+
+    #[test]
+    fn radian_request_length_limit() {
+        let command = [0u8; MAX_COMMAND_LEN + 1];
+        let mut frame = [0u8; 512];
+        let request = RadianRequest::new(Address::meter(16, 1087372), &command[..MAX_COMMAND_LEN]);
+        assert_eq!(request.to_frame_into(&mut frame), Some(255));
+        assert_eq!(frame[0], 255);
+        assert!(check_crc(&frame[..255]));
+        assert_eq!(request.to_frame_into(&mut frame[..254]), None);
+
+        let request = RadianRequest::new(Address::meter(16, 1087372), &command);
+        assert_eq!(request.to_frame_into(&mut frame), None);
+        assert_eq!(request.encode_into(&mut [0u8; 1024]), None);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn no_alloc_variants_work() {
+        let request = RadianRequest::new(Address::meter(16, 1087372), &COMMAND_READ_DATA);
+        let mut encoded = [0u8; 64];
+        let len = request.encode_into(&mut encoded).unwrap();
+        assert_eq!(len, request.encoded_len());
+        assert_eq!(request.encode_into(&mut encoded[..len - 1]), None);
+        assert_eq!(encoded[..len], make_radian_master_req(16, 1087372)[..]);
+
+        let frame = request.to_frame();
+        let samples = oversample(&frame);
+        let mut decoded = [0u8; 8];
+        let stats = decode_oversampled_into(&samples, &mut decoded);
+        assert_eq!(stats.len, 8);
+        assert_eq!(stats.min_confidence, 1.0);
+        assert_eq!(decoded, frame[..8]);
+    }
+
+    #[test]
+    fn encode2serial_1_3_into_works() {
+        let mut encoded = [0u8; 30];
+        assert_eq!(encoded_len(REQUEST_FRAME.len()), encoded.len());
+        assert_eq!(encode2serial_1_3_into(&REQUEST_FRAME, &mut encoded), 30);
+        assert_eq!(encoded, REQUEST_ENCODED);
+    }
+
+    #[test]
+    fn radian_request_encode_into_works() {
+        let request = RadianRequest::new(Address::meter(16, 1087372), &COMMAND_READ_DATA);
+        let mut frame = [0u8; 19];
+        assert_eq!(request.to_frame_into(&mut frame), Some(19));
+        assert_eq!(frame, REQUEST_FRAME);
+
+        let mut encoded = [0u8; 39];
+        assert_eq!(request.encode_into(&mut encoded), Some(39));
+        assert_eq!(encoded[..9], SYNCH_PATTERN);
+        assert_eq!(encoded[9..], REQUEST_ENCODED);
+    }
+
+    #[test]
+    fn decode_oversampled_into_works() {
+        let mut samples = [0u8; 128];
+        let len = oversample_into(&REQUEST_FRAME, &mut samples);
+
+        let mut decoded = [0u8; 32];
+        let stats = decode_oversampled_into(&samples[..len], &mut decoded);
+        assert_eq!(stats.len, REQUEST_FRAME.len());
+        assert_eq!(stats.min_confidence, 1.0);
+        assert_eq!(stats.framing_errors, 0);
+        assert!(!stats.truncated);
+        assert_eq!(decoded[..stats.len], REQUEST_FRAME);
+
+        // stops once the output is full
+        let mut decoded = [0u8; 8];
+        let stats = decode_oversampled_into(&samples[..len], &mut decoded);
+        assert_eq!(stats.len, 8);
+        assert_eq!(decoded, REQUEST_FRAME[..8]);
+    }
 }
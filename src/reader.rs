@@ -0,0 +1,358 @@
+//! A meter read from the configuration to the state to publish: duty cycle, exchange,
+//! calibration, consumption history, battery, listening schedule and leak rules.
+
+use crate::airtime::{self, AirtimeLedger};
+use crate::battery::{self, BatteryStatus};
+use crate::capture::Capture;
+use crate::config::Config;
+use crate::exchange::{self, ExchangeError, RadianLink};
+use crate::history::{Calibration, Consumption, History, Reading};
+use crate::leak::{self, LeakStatus};
+use crate::mqtt::{MqttError, MqttPublisher, StatePayload};
+use crate::radian::{make_radian_master_req, MeterData};
+use crate::schedule::{self, ListeningSchedule};
+use crate::tune::{self, TuneError, Tuning};
+use crate::CC1101;
+use chrono::{Local, Utc};
+use log::{info, warn};
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum ReadError {
+    Airtime(String),   // the ledger cannot be loaded or saved, nothing was transmitted
+    DutyCycle(String), // out of budget for the last hour
+    Exchange(ExchangeError),
+}
+
+impl std::fmt::Display for ReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadError::Airtime(e) | ReadError::DutyCycle(e) => write!(f, "{}", e),
+            ReadError::Exchange(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+impl From<ExchangeError> for ReadError {
+    fn from(err: ExchangeError) -> Self {
+        ReadError::Exchange(err)
+    }
+}
+
+/// Airtime ledger of the configured meter, saved after every transmission. It fails closed:
+/// without it the airtime of the last hour is unknown.
+pub struct DutyCycle {
+    ledger: AirtimeLedger,
+    airtime_file: PathBuf,
+    budget_ms: u32,
+    airtime_ms: u32, // of one request
+}
+
+impl DutyCycle {
+    /// Load the ledger and check it can be saved before anything is transmitted.
+    pub fn load(config: &Config) -> Result<Self, ReadError> {
+        let airtime_file = config.advanced.airtime_file.clone();
+        let ledger = AirtimeLedger::load(&airtime_file).map_err(|e| {
+            ReadError::Airtime(format!(
+                "Not transmitting without the airtime ledger {}: {}",
+                airtime_file.display(),
+                e
+            ))
+        })?;
+        let duty_cycle = Self {
+            ledger,
+            airtime_file,
+            budget_ms: airtime::budget_ms(config.radio.duty_cycle_percent),
+            airtime_ms: airtime::transmit_airtime_ms(
+                config.radio.wakeup_ms,
+                make_radian_master_req(config.meter.year, config.meter.serial).len(),
+            ),
+        };
+        duty_cycle.save()?;
+        Ok(duty_cycle)
+    }
+
+    /// Whether the budget of the last hour allows one more request.
+    pub fn check(&self) -> Result<(), ReadError> {
+        if self
+            .ledger
+            .allows(self.airtime_ms, self.budget_ms, Utc::now())
+        {
+            return Ok(());
+        }
+        Err(ReadError::DutyCycle(format!(
+            "Duty cycle budget exceeded: {} of {} ms of airtime used in the last hour",
+            self.ledger.used_ms(Utc::now()),
+            self.budget_ms
+        )))
+    }
+
+    /// Run an exchange if the budget allows it, and record its airtime unless the busy channel
+    /// kept it from transmitting.
+    pub fn transmit<T>(
+        &mut self,
+        exchange: impl FnOnce() -> Result<T, ExchangeError>,
+    ) -> Result<T, ReadError> {
+        self.check()?;
+        let result = exchange();
+        if !matches!(result, Err(ExchangeError::ChannelBusy(_))) {
+            self.ledger.record(self.airtime_ms, Utc::now());
+            self.save()?;
+        }
+        Ok(result?)
+    }
+
+    fn save(&self) -> Result<(), ReadError> {
+        self.ledger.save(&self.airtime_file).map_err(|e| {
+            ReadError::Airtime(format!(
+                "Not transmitting, the airtime ledger {} cannot be saved: {}",
+                self.airtime_file.display(),
+                e
+            ))
+        })
+    }
+}
+
+/// Everything published about the meter after a read.
+#[derive(Debug)]
+pub struct MeterState {
+    pub meter_data: MeterData,
+    pub consumption: Consumption,
+    pub battery_status: BatteryStatus,
+    pub listening_schedule: ListeningSchedule,
+    pub leak_status: Option<LeakStatus>,
+}
+
+impl MeterState {
+    pub fn payload(&self) -> StatePayload<'_> {
+        StatePayload {
+            meter_data: &self.meter_data,
+            consumption: &self.consumption,
+            battery_status: &self.battery_status,
+            listening_schedule: &self.listening_schedule,
+            leak_status: self.leak_status.as_ref(),
+        }
+    }
+}
+
+/// Read the configured meter over `link` and update the consumption history.
+pub fn read_meter(config: &Config, link: &impl RadianLink) -> Result<MeterState, ReadError> {
+    let (year, serial) = (config.meter.year, config.meter.serial);
+    let mut duty_cycle = DutyCycle::load(config)?;
+    duty_cycle.check()?;
+
+    info!("Reading meter serial={} year={}", serial, year);
+    let mut capture = config
+        .advanced
+        .capture_dir
+        .as_ref()
+        .map(|_| Capture::new(year, serial));
+    let result =
+        duty_cycle.transmit(|| exchange::get_meter_data(link, year, serial, capture.as_mut()));
+    if let (Some(capture), Some(capture_dir)) = (&capture, &config.advanced.capture_dir) {
+        match capture.save(capture_dir) {
+            Ok(path) => info!("Raw frames captured to {}", path.display()),
+            Err(e) => warn!("Raw frames not captured: {}", e),
+        }
+    }
+    let mut meter_data = result?;
+    meter_data.liters = config.meter.calibrated_liters(meter_data.raw_liters);
+    info!("Meter data read successfully:\n{:?}", meter_data);
+
+    let now = Utc::now().with_timezone(&Local);
+    let mut history = load_history(config);
+    let consumption = history.consumption(meter_data.liters, &now);
+    info!("Consumption since previous readings:\n{:?}", consumption);
+    history.record(
+        Reading {
+            timestamp: now.to_utc(),
+            liters: meter_data.liters,
+        },
+        &now,
+    );
+    save_history(config, &history);
+
+    let battery_status = battery::battery_status(
+        &meter_data,
+        config.meter.battery_warning_months,
+        now.to_utc(),
+    );
+    if battery_status.battery_low {
+        warn!(
+            "Meter battery is low: {} months left",
+            meter_data.battery_left
+        );
+    }
+
+    let listening_schedule = schedule::listening_schedule(&meter_data);
+
+    let leak_status = config.leak_detection.as_ref().map(|leak_detection| {
+        let status = leak::detect(leak_detection, history.readings(), &now);
+        if status.leak {
+            warn!("Possible leak detected: {}", status.leak_reasons.join("; "));
+        }
+        status
+    });
+
+    Ok(MeterState {
+        meter_data,
+        consumption,
+        battery_status,
+        listening_schedule,
+        leak_status,
+    })
+}
+
+/// Publish the discovery messages and the state of a read, then disconnect.
+pub fn publish(config: &Config, state: &MeterState) -> Result<(), MqttError> {
+    let mut publisher = MqttPublisher::new(config.mqtt.clone(), config.homeassistant.clone())?;
+
+    info!("Publishing Home Assistant discovery messages");
+    publisher.publish_discovery(&config.meter, config.leak_detection.is_some())?;
+
+    info!("Publishing meter state");
+    publisher.publish_state(&state.payload())?;
+
+    // Wait for messages to be transmitted before exiting
+    publisher.disconnect();
+
+    Ok(())
+}
+
+/// Tune the receiver of `cc1101` on the configured meter, within the duty cycle budget.
+pub fn tune_receiver(config: &Config, cc1101: &mut CC1101) -> Result<Tuning, TuneError> {
+    let (year, serial) = (config.meter.year, config.meter.serial);
+    let mut duty_cycle = DutyCycle::load(config).map_err(|e| TuneError::Stopped(e.to_string()))?;
+    let profile = cc1101.profile().clone();
+
+    tune::tune(&profile, |candidate| {
+        cc1101.set_profile(candidate.clone());
+        match duty_cycle.transmit(|| tune::read_freq_ests(&*cc1101, year, serial)) {
+            Ok(freq_ests) => Ok(Some(freq_ests)),
+            Err(ReadError::Exchange(e)) => {
+                info!("Read failed: {}", e);
+                Ok(None)
+            }
+            Err(e) => Err(TuneError::Stopped(e.to_string())),
+        }
+    })
+}
+
+// A broken history file must not prevent publishing the reading itself
+fn load_history(config: &Config) -> History {
+    let mut history = History::load(&config.advanced.state_file).unwrap_or_else(|e| {
+        warn!("Starting with an empty history: {}", e);
+        History::default()
+    });
+    let meter = &config.meter;
+    if history.set_calibration(Calibration {
+        year: meter.year,
+        serial: meter.serial,
+        index_offset: meter.index_offset,
+        pulse_weight: meter.pulse_weight,
+    }) {
+        warn!("Starting with an empty history: the meter or its calibration changed");
+    }
+    history
+}
+
+fn save_history(config: &Config, history: &History) {
+    let state_file = &config.advanced.state_file;
+    if let Err(e) = history.save(state_file) {
+        warn!("History not saved to {}: {}", state_file.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LeakDetectionConfig;
+    use crate::emulator::{MeterEmulator, SimulatedLink};
+    use std::fs;
+
+    fn config(name: &str) -> Config {
+        let dir = std::env::temp_dir();
+        let pid = std::process::id();
+        toml::from_str(&format!(
+            r#"
+            [mqtt]
+            broker_url = "mqtt://localhost:1883"
+            [homeassistant]
+            node_id = "water"
+            device_name = "Water Meter"
+            [meter]
+            serial = 1087372
+            year = 16
+            pulse_weight = 10
+            [advanced]
+            state_file = "{}"
+            airtime_file = "{}"
+            "#,
+            dir.join(format!("reader_{}_{}_state.json", name, pid))
+                .display(),
+            dir.join(format!("reader_{}_{}_airtime.json", name, pid))
+                .display(),
+        ))
+        .unwrap()
+    }
+
+    fn cleanup(config: &Config) {
+        let _ = fs::remove_file(&config.advanced.state_file);
+        let _ = fs::remove_file(&config.advanced.airtime_file);
+    }
+
+    #[test]
+    fn read_meter_works() {
+        let mut config = config("works");
+        config.leak_detection = Some(LeakDetectionConfig {
+            continuous_flow_reads: Some(24),
+            night_flow: None,
+        });
+        let link = SimulatedLink::new(MeterEmulator::new(16, 1087372));
+
+        let state = read_meter(&config, &link);
+        let history = History::load(&config.advanced.state_file).unwrap();
+        let ledger = AirtimeLedger::load(&config.advanced.airtime_file).unwrap();
+        cleanup(&config);
+
+        let state = state.unwrap();
+        assert_eq!(
+            state.meter_data.liters,
+            state.meter_data.raw_liters as i64 * 10
+        );
+        assert_eq!(history.readings().len(), 1);
+        assert!(!state.leak_status.unwrap().leak);
+        assert_eq!(ledger.used_ms(Utc::now()), 2130);
+    }
+
+    #[test]
+    fn read_meter_resets_history_on_calibration_change() {
+        let mut config = config("calibration");
+        let link = SimulatedLink::new(MeterEmulator::new(16, 1087372));
+        read_meter(&config, &link).unwrap();
+        let second = read_meter(&config, &link).unwrap();
+        config.meter.index_offset = 1000;
+        let recalibrated = read_meter(&config, &link).unwrap();
+        let history = History::load(&config.advanced.state_file).unwrap();
+        cleanup(&config);
+
+        assert_eq!(second.consumption.consumption_since_last_read, Some(0));
+        assert_eq!(recalibrated.consumption.consumption_since_last_read, None);
+        assert_eq!(history.readings().len(), 1);
+    }
+
+    #[test]
+    fn read_meter_needs_the_airtime_ledger() {
+        let config = config("ledger");
+        fs::write(&config.advanced.airtime_file, "{").unwrap();
+        let link = SimulatedLink::new(MeterEmulator::new(16, 1087372));
+
+        let result = read_meter(&config, &link);
+        cleanup(&config);
+
+        assert!(matches!(result, Err(ReadError::Airtime(_))));
+        assert_eq!(link.elapsed_ms(), 0);
+    }
+}
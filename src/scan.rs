@@ -0,0 +1,130 @@
+//! RSSI sweep around the RADIAN carrier, to tell interference from antenna or frequency problems.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fmt::Write;
+
+// Bars of the rendered spectrum start at this level, one character per 2 dB
+const BAR_FLOOR_DBM: i32 = -120;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct SpectrumPoint {
+    pub frequency_hz: u32,
+    pub rssi_dbm: i8,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Spectrum {
+    pub timestamp: DateTime<Utc>,
+    pub carrier_hz: u32,
+    /// Median of the sweep, a few interferers do not move it
+    pub noise_floor_dbm: i8,
+    pub peak: SpectrumPoint,
+    pub points: Vec<SpectrumPoint>,
+}
+
+/// Frequencies `step_khz` apart over `span_khz` centered on `carrier_hz`, carrier included.
+pub fn sweep_frequencies(carrier_hz: u32, span_khz: u32, step_khz: u32) -> Vec<u32> {
+    let steps = (span_khz / step_khz / 2) as i64;
+    (-steps..=steps)
+        .map(|step| (carrier_hz as i64 + step * step_khz as i64 * 1000) as u32)
+        .collect()
+}
+
+impl Spectrum {
+    pub fn new(carrier_hz: u32, points: Vec<SpectrumPoint>) -> Self {
+        let mut levels: Vec<i8> = points.iter().map(|p| p.rssi_dbm).collect();
+        levels.sort_unstable();
+        let noise_floor_dbm = levels.get(levels.len().saturating_sub(1) / 2).copied();
+        let peak = points.iter().max_by_key(|p| p.rssi_dbm).copied();
+
+        Spectrum {
+            timestamp: Utc::now(),
+            carrier_hz,
+            noise_floor_dbm: noise_floor_dbm.unwrap_or(i8::MIN),
+            peak: peak.unwrap_or(SpectrumPoint {
+                frequency_hz: carrier_hz,
+                rssi_dbm: i8::MIN,
+            }),
+            points,
+        }
+    }
+
+    /// One line per frequency with a bar of its level, the carrier marked, then the summary.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for point in &self.points {
+            let bar = (point.rssi_dbm as i32 - BAR_FLOOR_DBM).max(0) as usize / 2;
+            let marker = if point.frequency_hz == self.carrier_hz {
+                " <- carrier"
+            } else {
+                ""
+            };
+            writeln!(
+                out,
+                "{:>8.3} MHz {:>5} dBm |{}{}",
+                point.frequency_hz as f64 / 1e6,
+                point.rssi_dbm,
+                "#".repeat(bar),
+                marker
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "Noise floor: {} dBm, peak: {} dBm at {:.3} MHz",
+            self.noise_floor_dbm,
+            self.peak.rssi_dbm,
+            self.peak.frequency_hz as f64 / 1e6
+        )
+        .unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sweep_frequencies_works() {
+        let frequencies = sweep_frequencies(433_819_854, 40, 10);
+        assert_eq!(
+            frequencies,
+            vec![
+                433_799_854,
+                433_809_854,
+                433_819_854,
+                433_829_854,
+                433_839_854
+            ]
+        );
+        assert_eq!(sweep_frequencies(433_819_854, 5, 10), vec![433_819_854]);
+    }
+
+    #[test]
+    fn spectrum_with_interferer() {
+        let points = [
+            (433_800_000, -104),
+            (433_810_000, -101),
+            (433_820_000, -55),
+            (433_830_000, -103),
+            (433_840_000, -102),
+        ]
+        .iter()
+        .map(|&(frequency_hz, rssi_dbm)| SpectrumPoint {
+            frequency_hz,
+            rssi_dbm,
+        })
+        .collect();
+        let spectrum = Spectrum::new(433_820_000, points);
+        assert_eq!(spectrum.noise_floor_dbm, -102);
+        assert_eq!(spectrum.peak.frequency_hz, 433_820_000);
+
+        let render = spectrum.render();
+        assert!(render
+            .contains(" 433.820 MHz   -55 dBm |################################ <- carrier\n"));
+        assert!(render.contains(" 433.800 MHz  -104 dBm |########\n"));
+        assert!(render.ends_with("Noise floor: -102 dBm, peak: -55 dBm at 433.820 MHz\n"));
+    }
+}
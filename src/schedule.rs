@@ -0,0 +1,52 @@
+//! Hours during which the meter listens for requests.
+
+use crate::radian::MeterData;
+use serde::Serialize;
+
+// Whether the meter is listening right now is not published: the state is only sent after a
+// read, any value would be stale within the hour. Home Assistant derives it from `time_start`
+// and `time_end`, see the README.
+#[derive(Debug, Serialize)]
+pub struct ListeningSchedule {
+    pub listening_schedule: String,
+}
+
+/// Describe the hours during which the meter answers requests.
+/// The known frame layout only carries start and end hours, the window
+/// is assumed to apply every day of the week.
+pub fn listening_schedule(meter_data: &MeterData) -> ListeningSchedule {
+    ListeningSchedule {
+        listening_schedule: format!(
+            "{:02}:00–{:02}:00",
+            meter_data.time_start, meter_data.time_end
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meter_data(time_start: i32, time_end: i32) -> MeterData {
+        MeterData {
+            liters: 0,
+            raw_liters: 0,
+            reads_counter: 0,
+            battery_left: 100,
+            time_start,
+            time_end,
+        }
+    }
+
+    #[test]
+    fn listening_schedule_works() {
+        let schedule = listening_schedule(&meter_data(6, 18));
+        assert_eq!(schedule.listening_schedule, "06:00–18:00");
+
+        // Window spanning midnight
+        assert_eq!(
+            listening_schedule(&meter_data(20, 4)).listening_schedule,
+            "20:00–04:00"
+        );
+    }
+}
@@ -0,0 +1,79 @@
+//! Identification of RADIAN frames exchanged between other readers and meters.
+
+use crate::exchange::LinkQuality;
+use crate::radian::{check_crc, decode_oversampled, Address, CONTROL_REQUEST, CONTROL_RESPONSE};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SniffedKind {
+    Request,  // from a reader to a meter
+    Response, // from a meter to a reader
+}
+
+/// A RADIAN frame heard on the air, identified by the meter it was addressed to or sent from.
+#[derive(Debug, Serialize)]
+pub struct SniffedFrame {
+    pub timestamp: DateTime<Utc>,
+    pub kind: SniffedKind,
+    pub year: u8,
+    pub serial: u32,
+    pub crc_valid: Option<bool>, // None when the frame was cut before its CRC
+    #[serde(flatten)]
+    pub link_quality: LinkQuality,
+    #[serde(with = "hex")]
+    pub payload: Vec<u8>,
+}
+
+/// Decode a raw oversampled buffer and identify the meter involved.
+/// Returns None for noise or frames too short to carry both addresses.
+pub fn identify(rx_buffer: &[u8], link_quality: LinkQuality) -> Option<SniffedFrame> {
+    let mut frame = decode_oversampled(rx_buffer).complete_bytes().to_vec();
+    let declared = *frame.first()? as usize;
+    if declared < 14 || frame.len() < 14 {
+        return None;
+    }
+    frame.truncate(declared);
+
+    // Meter address is the destination of a request and the source of a response
+    let (kind, address) = match frame[1] {
+        CONTROL_REQUEST => (SniffedKind::Request, Address::from_bytes(&frame[2..8])),
+        CONTROL_RESPONSE => (SniffedKind::Response, Address::from_bytes(&frame[8..14])),
+        _ => return None,
+    };
+
+    Some(SniffedFrame {
+        timestamp: Utc::now(),
+        kind,
+        year: address.year,
+        serial: address.serial,
+        crc_valid: (frame.len() == declared).then(|| check_crc(&frame)),
+        link_quality,
+        payload: frame,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identify_response() {
+        let rx_buffer = hex::decode("00fffff0ff8780078007fc000000003fe1e1e001e1ff000000f00ff8078780007fc00001e3c1fe001e0f000ff0000000007fc3c7c007c3fe01ffe0001ff0ffff0000ff800787807ffc0003ffffffe000000001ff0f0000000ff87fff80007fc000000003fe00001e1e1ff0f0f00fffff8000078007fc000000003fe0000001e0ff00ff800007f87c7c7c003fe3e0000001ff00f0ff000ff87f8000007fc00003c003fe1e1fe0001ff0ff000f00ff807ff87807fc00003fc03fe01e01fe01ff00f00ff00ff800007f807fc3fc03fc03fe00001fe01ff00f0000f0ff8078000787fc003c3fc03fe1e001fe01ff0000000007f8000000003fc01fe00001ff00e00f000ff8007800007fc3c0000003fe1e00001e1ff000f00000ff8000000007fc000000003fe000000001ff000000000ff8000000007fc000000003fe00000001fff00000000fff800000003ffe00000001fff00000000fff800000007ffc00000003ffe00000001fff00000000fff800000007ffc00000003ffe00000001fff00000000fff800000007ffc0003fe3fffe1f1e0001fff0ffff80007f8000000003fe003fe1fffff000fff00fff87fff80007fc000000003fe01e01e001ff0f000ff0fff87fff80007fc000000003fe01ffffe01ff000ff00ffff87fff80007fc000000003fe01fe01e00ff0fff007ffff87fffc0003fe000000001ff0f1fffff0ff8078000007fc00003c003fe000000001ff0f0f000ffff87ffff8007fc00003c003fe000000001ff0f0f000ffff8007800787fc00003e003fe000000001ff0f8ff8ff8ffc07ffc3fc3fc00001e001ff000000000ff87f87ffffffc3c3c0003ffe00001e001ff000000000ff87f8078787fc3c0003c3ffe00001e001ff000000000ff8078787ffffc03c3ffc3ffe00001f001ff0000000007f87c787f807fc3fc03e3fffe00001f000ff8000000007fc03fffc").unwrap();
+
+        let sniffed = identify(&rx_buffer, LinkQuality::default()).unwrap();
+        assert_eq!(sniffed.kind, SniffedKind::Response);
+        assert_eq!(sniffed.year, 14);
+        assert_eq!(sniffed.serial, 1021176);
+        // the capture ends in the first CRC byte
+        assert_eq!(sniffed.crc_valid, None);
+        assert_eq!(sniffed.payload.len(), 122);
+    }
+
+    #[test]
+    fn identify_ignores_noise() {
+        assert!(identify(&[0xFF; 64], LinkQuality::default()).is_none());
+        assert!(identify(&[0x00, 0xF0, 0x0F, 0xAA], LinkQuality::default()).is_none());
+    }
+}
@@ -0,0 +1,307 @@
+//! Receiver tuning from repeated reads: frequency offset compensation, then the narrowest RX
+//! filter bandwidth and the data rate offset that still decode every read.
+//!
+//! The CC1101 reports the frequency error of each frame (FREQEST) but not its deviation, so the
+//! bandwidth the deviation needs is found by trying, narrowest first.
+
+use crate::capture::Capture;
+use crate::cc1101_const::*;
+use crate::exchange::{get_meter_data, ExchangeError, RadianLink};
+use crate::profile::{get, register_address, set, RegisterProfile};
+use log::info;
+
+/// Reads at each setting, all of them must decode for the setting to be kept.
+pub const TUNE_READS: usize = 3;
+
+// DRATE_M steps tried on the receive phases, about 0.26% each
+const DATA_RATE_OFFSETS: [i8; 5] = [0, -1, 1, -2, 2];
+
+// FREQEST and FSCTRL0 resolution: f_XOSC / 2^14
+const FREQ_STEP_HZ: f64 = 26_000_000.0 / 16384.0;
+
+#[derive(Debug, PartialEq)]
+pub enum TuneError {
+    NoReading,       // the meter never answered with the current settings
+    NotReliable,     // no setting decoded every read
+    Stopped(String), // reading aborted, e.g. out of duty cycle budget
+}
+
+impl std::fmt::Display for TuneError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TuneError::NoReading => write!(
+                f,
+                "Tuning needs the meter to answer with the current settings, none of {} reads did",
+                TUNE_READS
+            ),
+            TuneError::NotReliable => write!(
+                f,
+                "No receiver setting decoded {} reads in a row, the configuration is left unchanged",
+                TUNE_READS
+            ),
+            TuneError::Stopped(e) => write!(f, "Tuning stopped: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TuneError {}
+
+#[derive(Debug)]
+pub struct Tuning {
+    /// Mean frequency error measured before compensation
+    pub freq_offset_hz: i32,
+    /// Between the lowest and highest error of the frames received
+    pub freq_spread_hz: u32,
+    pub bandwidth_hz: u32,
+    /// DRATE_M steps added to the receive phases
+    pub data_rate_offset: i8,
+    pub profile: RegisterProfile,
+}
+
+impl Tuning {
+    /// Registers to store in the radio profile configuration, as (phase, register, value).
+    pub fn overrides(&self) -> Vec<(&'static str, &'static str, u8)> {
+        let mut overrides = Vec::new();
+        for (phase, registers, names) in [
+            ("base", &self.profile.base, &["FSCTRL0", "MDMCFG4"][..]),
+            (
+                "sync_search",
+                &self.profile.sync_search,
+                &["MDMCFG4", "MDMCFG3"],
+            ),
+            ("data", &self.profile.data, &["MDMCFG4", "MDMCFG3"]),
+        ] {
+            for &name in names {
+                if let Some(value) = register_address(name).and_then(|r| get(registers, r)) {
+                    overrides.push((phase, name, value));
+                }
+            }
+        }
+        overrides
+    }
+}
+
+/// RX filter bandwidth set by MDMCFG4: f_XOSC / (8 * (4 + CHANBW_M) * 2^CHANBW_E).
+pub fn bandwidth_hz(mdmcfg4: u8) -> u32 {
+    let chanbw_e = (mdmcfg4 >> 6) as u32;
+    let chanbw_m = ((mdmcfg4 >> 4) & 0x03) as u32;
+    26_000_000 / ((8 * (4 + chanbw_m)) << chanbw_e)
+}
+
+/// One tuning read over `link`: the FREQEST of each frame received. It only succeeds when the data
+/// frame passed its length, CRC and source checks, a corrupted frame does not count as decoded.
+pub fn read_freq_ests(
+    link: &impl RadianLink,
+    year: u8,
+    serial: u32,
+) -> Result<Vec<u8>, ExchangeError> {
+    let mut capture = Capture::new(year, serial);
+    get_meter_data(link, year, serial, Some(&mut capture))?;
+    Ok(capture
+        .frames
+        .iter()
+        .filter(|frame| frame.byte_count > 0)
+        .map(|frame| frame.link_quality.freq_est)
+        .collect())
+}
+
+/// Tune the receive settings of `profile`. `read` runs one exchange with the given profile and
+/// returns the FREQEST of each frame received, or None if the read failed, see
+/// [`read_freq_ests`].
+pub fn tune(
+    profile: &RegisterProfile,
+    mut read: impl FnMut(&RegisterProfile) -> Result<Option<Vec<u8>>, TuneError>,
+) -> Result<Tuning, TuneError> {
+    let mut freq_ests = Vec::new();
+    for _ in 0..TUNE_READS {
+        if let Some(frames) = read(profile)? {
+            freq_ests.extend(frames.into_iter().map(|freq_est| freq_est as i8 as i32));
+        }
+    }
+    let (Some(&min), Some(&max)) = (freq_ests.iter().min(), freq_ests.iter().max()) else {
+        return Err(TuneError::NoReading);
+    };
+    let mean = freq_ests.iter().sum::<i32>() as f64 / freq_ests.len() as f64;
+    let freq_offset_hz = (mean * FREQ_STEP_HZ).round() as i32;
+    let freq_spread_hz = ((max - min) as f64 * FREQ_STEP_HZ).round() as u32;
+    info!(
+        "Frequency error: {} Hz, spread {} Hz",
+        freq_offset_hz, freq_spread_hz
+    );
+
+    let mut compensated = profile.clone();
+    let freqoff = get(&profile.base, FSCTRL0).unwrap_or(0) as i8;
+    set(
+        &mut compensated.base,
+        FSCTRL0,
+        freqoff.saturating_add(mean.round() as i8) as u8,
+    );
+
+    let current_hz = get(&profile.sync_search, MDMCFG4).map_or(u32::MAX, bandwidth_hz);
+    let mut bandwidths: Vec<u8> = (0..16).map(|chanbw| chanbw << 4).collect();
+    bandwidths.retain(|&chanbw| bandwidth_hz(chanbw) <= current_hz);
+    bandwidths.sort_by_key(|&chanbw| bandwidth_hz(chanbw));
+
+    for chanbw in bandwidths {
+        for data_rate_offset in DATA_RATE_OFFSETS {
+            let Some(candidate) = with_receiver(&compensated, chanbw, data_rate_offset) else {
+                continue;
+            };
+            info!(
+                "Trying {} kHz RX filter, data rate offset {}",
+                bandwidth_hz(chanbw) / 1000,
+                data_rate_offset
+            );
+            if decodes_every_read(&candidate, &mut read)? {
+                return Ok(Tuning {
+                    freq_offset_hz,
+                    freq_spread_hz,
+                    bandwidth_hz: bandwidth_hz(chanbw),
+                    data_rate_offset,
+                    profile: candidate,
+                });
+            }
+        }
+    }
+
+    Err(TuneError::NotReliable)
+}
+
+fn decodes_every_read(
+    profile: &RegisterProfile,
+    read: &mut impl FnMut(&RegisterProfile) -> Result<Option<Vec<u8>>, TuneError>,
+) -> Result<bool, TuneError> {
+    for _ in 0..TUNE_READS {
+        if read(profile)?.is_none() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// The bandwidth applies to every phase but TX, the data rate offset only to the receive phases:
+// the meter is still addressed at the nominal rate. None if DRATE_M would overflow.
+fn with_receiver(
+    profile: &RegisterProfile,
+    chanbw: u8,
+    data_rate_offset: i8,
+) -> Option<RegisterProfile> {
+    let mut candidate = profile.clone();
+    for registers in [
+        &mut candidate.base,
+        &mut candidate.sync_search,
+        &mut candidate.data,
+    ] {
+        if let Some(mdmcfg4) = get(registers, MDMCFG4) {
+            set(registers, MDMCFG4, chanbw | (mdmcfg4 & 0x0F));
+        }
+    }
+    for registers in [&mut candidate.sync_search, &mut candidate.data] {
+        if let Some(drate_m) = get(registers, MDMCFG3) {
+            set(
+                registers,
+                MDMCFG3,
+                drate_m.checked_add_signed(data_rate_offset)?,
+            );
+        }
+    }
+    Some(candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{MeterEmulator, SimulatedLink};
+    use std::cell::Cell;
+
+    // A meter `offset_hz` off the carrier, with a clock `rate_error` DRATE_M steps off. Its
+    // frames spread 6.5 kHz on each side of its carrier (2.4 kbps, 5.25 kHz deviation) and
+    // decode every time at the right data rate, every other time one step off.
+    fn meter(
+        offset_hz: i32,
+        rate_error: i8,
+        reads: &Cell<usize>,
+    ) -> impl FnMut(&RegisterProfile) -> Result<Option<Vec<u8>>, TuneError> + '_ {
+        let nominal = RegisterProfile::default();
+        move |profile| {
+            reads.set(reads.get() + 1);
+            let freqoff = get(&profile.base, FSCTRL0).unwrap_or(0) as i8 as f64;
+            let residual_hz = offset_hz as f64 - freqoff * FREQ_STEP_HZ;
+            let half_bw = bandwidth_hz(get(&profile.data, MDMCFG4).unwrap()) as f64 / 2.0;
+            let drate_offset = get(&profile.data, MDMCFG3).unwrap() as i16
+                - get(&nominal.data, MDMCFG3).unwrap() as i16;
+            let rate_miss = (drate_offset - rate_error as i16).abs();
+
+            let decoded = residual_hz.abs() + 6_500.0 <= half_bw
+                && (rate_miss == 0 || rate_miss == 1 && reads.get() % 2 == 1);
+            let freq_est = (residual_hz / FREQ_STEP_HZ).round() as i8 as u8;
+            Ok(decoded.then(|| vec![freq_est, freq_est]))
+        }
+    }
+
+    #[test]
+    fn bandwidth_hz_works() {
+        assert_eq!(bandwidth_hz(0xF8), 58_035);
+        assert_eq!(bandwidth_hz(0x66), 270_833);
+        assert_eq!(bandwidth_hz(0x08), 812_500);
+    }
+
+    #[test]
+    fn tune_compensates_and_narrows() {
+        let mut profile = RegisterProfile::default();
+        for registers in [&mut profile.sync_search, &mut profile.data] {
+            let mdmcfg4 = get(registers, MDMCFG4).unwrap();
+            set(registers, MDMCFG4, 0x60 | (mdmcfg4 & 0x0F));
+        }
+
+        let reads = Cell::new(0);
+        let tuning = tune(&profile, meter(40_000, 0, &reads)).unwrap();
+        assert_eq!(tuning.freq_offset_hz, 39_673);
+        assert_eq!(tuning.bandwidth_hz, 58_035);
+        assert_eq!(tuning.data_rate_offset, 0);
+        assert_eq!(reads.get(), 2 * TUNE_READS);
+        assert_eq!(
+            tuning.overrides(),
+            vec![
+                ("base", "FSCTRL0", 25),
+                ("base", "MDMCFG4", 0xF6),
+                ("sync_search", "MDMCFG4", 0xF6),
+                ("sync_search", "MDMCFG3", 0x83),
+                ("data", "MDMCFG4", 0xF8),
+                ("data", "MDMCFG3", 0x83),
+            ]
+        );
+    }
+
+    #[test]
+    fn tune_finds_data_rate_offset() {
+        let reads = Cell::new(0);
+        let tuning = tune(&RegisterProfile::default(), meter(0, 1, &reads)).unwrap();
+        assert_eq!(tuning.bandwidth_hz, 58_035);
+        assert_eq!(tuning.data_rate_offset, 1);
+        assert!(tuning.profile.data.contains(&(MDMCFG3, 0x84)));
+        assert!(tuning.profile.sync_search.contains(&(MDMCFG3, 0x84)));
+        assert!(tuning.profile.base.contains(&(MDMCFG3, 0x83)));
+    }
+
+    #[test]
+    fn tune_needs_a_reading() {
+        let reads = Cell::new(0);
+        let result = tune(&RegisterProfile::default(), meter(60_000, 0, &reads));
+        assert_eq!(result.unwrap_err(), TuneError::NoReading);
+        assert_eq!(reads.get(), TUNE_READS);
+    }
+
+    #[test]
+    fn read_freq_ests_needs_an_intact_data_frame() {
+        let mut link = SimulatedLink::new(MeterEmulator::new(16, 1087372));
+        link.link_quality.freq_est = 0xFB;
+        assert_eq!(read_freq_ests(&link, 16, 1087372), Ok(vec![0xFB, 0xFB]));
+
+        link.corrupt_data_frame = true;
+        assert!(matches!(
+            read_freq_ests(&link, 16, 1087372),
+            Err(ExchangeError::BadData(_))
+        ));
+    }
+}